@@ -19,10 +19,13 @@ use actix_web::guard;
 use serde::{Deserialize, Serialize};
 use serde_json;
 
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 
 use std::fmt::{Display, Formatter, Result};
 
+use config::Config;
+
 struct MyCounter {
     count: Mutex<i32>,
 }
@@ -122,6 +125,14 @@ fn users_config(config: &mut web::ServiceConfig) {
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
+    let mut config = Config::load("app-demo", "APP_DEMO");
+    let bind = config.get("server.bind", SocketAddr::from(([0, 0, 0, 0], 8888)));
+    config.finish()?;
+    if config.print {
+        print!("{}", config);
+        return Ok(());
+    }
+
     let my_counter = MyCounter {count: Mutex::new(0)};
     let my_counter_arc = Arc::new(my_counter);
 
@@ -135,7 +146,7 @@ async fn main() -> std::io::Result<()> {
                 web::resource("/index")
                     .route(web::get().to(index)))
     })
-    .bind(("0.0.0.0", 8888))?
+    .bind(bind)?
     .run()
     .await
 }
@@ -0,0 +1,672 @@
+//! Layered settings for the servers. Every setting has a default, which a
+//! TOML file, then an environment variable, then a command line flag can
+//! override.
+//!
+//! A setting `section.key` is written as `key` under `[section]` in the
+//! file, read from `<PREFIX>_SECTION_KEY` and given as `--section.key=value`
+//! on the command line. The file is `./<binary>.toml` unless `--config` or
+//! `<PREFIX>_CONFIG` names another one. `--print-config` prints the settings
+//! in effect, as a file that could be loaded back, and exits.
+//!
+//! The file is read by a small parser rather than a TOML library, and it
+//! takes only the part of TOML the settings need: `[table]` headers, bare
+//! keys, basic and literal strings on one line, booleans, and decimal
+//! integers and floats. Arrays, inline tables, multi-line strings, dates,
+//! quoted keys, `inf`, `nan` and hex, octal or binary numbers are refused.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// A value a setting can take
+pub trait Setting: Sized {
+    fn parse(value: &str) -> Result<Self, String>;
+    /// The value as it is written in the config file
+    fn show(&self) -> String;
+}
+
+/// Reads an environment variable
+type Env = Box<dyn Fn(&str) -> Option<String>>;
+
+/// Where a setting came from
+enum Source {
+    Default,
+    File,
+    Env(String),
+    Flag,
+}
+
+pub struct Config {
+    prefix: String,
+    path: String,
+    file: BTreeMap<String, String>,
+    flags: BTreeMap<String, String>,
+    env: Env,
+    /// Older environment variables still honoured, by setting
+    aliases: Vec<(&'static str, &'static str)>,
+    used: HashSet<String>,
+    /// Every setting read, in order, for `--print-config`
+    resolved: Vec<(String, Option<String>, Source)>,
+    errors: Vec<String>,
+    /// Set by `--print-config`
+    pub print: bool,
+}
+
+impl Config {
+    /// Settings from the process arguments and environment, and from the
+    /// config file of the binary `name`
+    pub fn load(name: &str, prefix: &str) -> Config {
+        Config::new(name, prefix, std::env::args().skip(1), |var| std::env::var(var).ok())
+    }
+
+    fn new<A, E>(name: &str, prefix: &str, args: A, env: E) -> Config
+    where
+        A: IntoIterator<Item = String>,
+        E: Fn(&str) -> Option<String> + 'static,
+    {
+        let mut config = Config {
+            prefix: prefix.to_owned(),
+            path: format!("./{}.toml", name),
+            file: BTreeMap::new(),
+            flags: BTreeMap::new(),
+            env: Box::new(env),
+            aliases: Vec::new(),
+            used: HashSet::new(),
+            resolved: Vec::new(),
+            errors: Vec::new(),
+            print: false,
+        };
+        let mut explicit = config.parse_args(args);
+        if explicit.is_none() {
+            explicit = (config.env)(&format!("{}_CONFIG", prefix));
+        }
+        let required = explicit.is_some();
+        if let Some(path) = explicit {
+            config.path = path;
+        }
+        match std::fs::read_to_string(&config.path) {
+            Ok(text) => match parse_toml(&text) {
+                Ok(file) => config.file = file,
+                Err(e) => config.errors.push(format!("{}: {}", config.path, e)),
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && !required => (),
+            Err(e) => config.errors.push(format!("Can not read {}: {}", config.path, e)),
+        }
+        config
+    }
+
+    /// Pick out `--config` and `--print-config`, keeping the other flags as
+    /// settings. Returns the config file asked for.
+    fn parse_args<A: IntoIterator<Item = String>>(&mut self, args: A) -> Option<String> {
+        let mut path = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let flag = match arg.strip_prefix("--") {
+                Some(flag) => flag,
+                None => {
+                    self.errors.push(format!("Unexpected argument {:?}", arg));
+                    continue;
+                }
+            };
+            if flag == "print-config" {
+                self.print = true;
+                continue;
+            }
+            let (key, value) = match flag.find('=') {
+                Some(at) => (&flag[..at], Some(flag[at + 1..].to_owned())),
+                None => (flag, None),
+            };
+            let value = match value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => {
+                    self.errors.push(format!("--{} needs a value", key));
+                    continue;
+                }
+            };
+            if key == "config" {
+                path = Some(value);
+            } else {
+                self.flags.insert(key.replace('-', "_"), value);
+            }
+        }
+        path
+    }
+
+    /// Keep reading an environment variable from before there was a config
+    /// file, for the given setting
+    pub fn alias(mut self, key: &'static str, var: &'static str) -> Config {
+        self.aliases.push((key, var));
+        self
+    }
+
+    /// The raw value of a setting from the highest layer that has one
+    fn lookup(&mut self, key: &str) -> Option<(String, Source)> {
+        self.used.insert(key.to_owned());
+        if let Some(value) = self.flags.get(key) {
+            return Some((value.clone(), Source::Flag));
+        }
+        let var = format!("{}_{}", self.prefix, key.replace('.', "_")).to_uppercase();
+        let aliases = self.aliases.iter().filter(|(aliased, _)| *aliased == key);
+        for var in std::iter::once(var).chain(aliases.map(|(_, var)| (*var).to_owned())) {
+            if let Some(value) = (self.env)(&var) {
+                return Some((value, Source::Env(var)));
+            }
+        }
+        self.file.get(key).map(|value| (value.clone(), Source::File))
+    }
+
+    fn parse<T: Setting>(&mut self, key: &str, value: &str, source: &Source) -> Option<T> {
+        match T::parse(value.trim()) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                let from = match source {
+                    Source::Default => String::from("default"),
+                    Source::File => self.path.clone(),
+                    Source::Env(var) => var.clone(),
+                    Source::Flag => format!("--{}", key),
+                };
+                self.errors.push(format!("{}: {} (from {})", key, e, from));
+                None
+            }
+        }
+    }
+
+    /// A setting, or its default when no layer sets it
+    pub fn get<T: Setting>(&mut self, key: &str, default: T) -> T {
+        let value = self
+            .lookup(key)
+            .and_then(|(raw, source)| self.parse(key, &raw, &source).map(|value| (value, source)));
+        let (value, source) = value.unwrap_or((default, Source::Default));
+        self.resolved.push((key.to_owned(), Some(value.show()), source));
+        value
+    }
+
+    /// A setting without a default, an empty value leaves it unset
+    pub fn optional<T: Setting>(&mut self, key: &str) -> Option<T> {
+        let value = self
+            .lookup(key)
+            .filter(|(raw, _)| !raw.trim().is_empty())
+            .and_then(|(raw, source)| self.parse::<T>(key, &raw, &source).map(|value| (value, source)));
+        match value {
+            Some((value, source)) => {
+                self.resolved.push((key.to_owned(), Some(value.show()), source));
+                Some(value)
+            }
+            None => {
+                self.resolved.push((key.to_owned(), None, Source::Default));
+                None
+            }
+        }
+    }
+
+    /// Like `optional`, but never printed
+    pub fn secret(&mut self, key: &str) -> Option<String> {
+        let value = self.optional::<String>(key);
+        if let Some(last) = self.resolved.last_mut() {
+            if value.is_some() {
+                last.1 = Some(String::from("\"********\""));
+            }
+        }
+        value
+    }
+
+    /// Record a problem with a setting unless `ok`
+    pub fn check(&mut self, key: &str, ok: bool, problem: &str) {
+        if !ok {
+            self.errors.push(format!("{}: {}", key, problem));
+        }
+    }
+
+    /// Report every problem found, including settings nothing asked for,
+    /// which are most likely typos
+    pub fn finish(&mut self) -> io::Result<()> {
+        let used = &self.used;
+        let unknown = self.file.keys().filter(|key| !used.contains(*key));
+        let unknown: Vec<_> = unknown.map(|key| format!("{}: unknown setting {}", self.path, key)).collect();
+        self.errors.extend(unknown);
+        let unknown = self.flags.keys().filter(|key| !used.contains(*key));
+        let unknown: Vec<_> = unknown.map(|key| format!("Unknown flag --{}", key)).collect();
+        self.errors.extend(unknown);
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+        for error in &self.errors {
+            println!("{}", error);
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid configuration"))
+    }
+}
+
+/// The settings in effect, as a config file noting where each came from
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Tables in the order their first setting was read
+        let mut tables: Vec<&str> = Vec::new();
+        for (key, _, _) in &self.resolved {
+            let table = key.rfind('.').map_or("", |at| &key[..at]);
+            if !tables.contains(&table) {
+                tables.push(table);
+            }
+        }
+        for (n, table) in tables.iter().enumerate() {
+            if n > 0 {
+                writeln!(f)?;
+            }
+            if !table.is_empty() {
+                writeln!(f, "[{}]", table)?;
+            }
+            for (key, value, source) in &self.resolved {
+                let (parent, name) = match key.rfind('.') {
+                    Some(at) => (&key[..at], &key[at + 1..]),
+                    None => ("", key.as_str()),
+                };
+                if parent != *table {
+                    continue;
+                }
+                let from = match source {
+                    Source::Default => String::new(),
+                    Source::File => format!("  # from {}", self.path),
+                    Source::Env(var) => format!("  # from {}", var),
+                    Source::Flag => format!("  # from --{}", key),
+                };
+                match value {
+                    Some(value) => writeln!(f, "{} = {}{}", name, value, from)?,
+                    None => writeln!(f, "# {} is not set", name)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Flat `section.key` values from the subset of TOML described at the top
+fn parse_toml(text: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut values = BTreeMap::new();
+    let mut table = String::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let at = |e: &str| format!("line {}: {}", n + 1, e);
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            let end = line.find(']').ok_or_else(|| at("unclosed table header"))?;
+            if !line[end + 1..].trim_start().starts_with('#') && !line[end + 1..].trim().is_empty() {
+                return Err(at("unexpected text after table header"));
+            }
+            table = line[1..end].trim().to_owned();
+            if !is_key(&table) {
+                return Err(at("invalid table name"));
+            }
+            continue;
+        }
+        let eq = line.find('=').ok_or_else(|| at("expected `key = value`"))?;
+        let key = line[..eq].trim();
+        if !is_key(key) {
+            return Err(at("invalid key"));
+        }
+        let value = parse_value(line[eq + 1..].trim()).map_err(|e| at(&e))?;
+        let key = if table.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}.{}", table, key)
+        };
+        if values.insert(key, value).is_some() {
+            return Err(at("duplicate key"));
+        }
+    }
+    Ok(values)
+}
+
+fn is_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.split('.').all(|part| {
+            !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        })
+}
+
+/// A value, with any comment after it dropped
+fn parse_value(text: &str) -> Result<String, String> {
+    let (value, rest) = match text.chars().next() {
+        Some('"') => {
+            let mut value = String::new();
+            let mut chars = text.char_indices().skip(1);
+            loop {
+                match chars.next() {
+                    Some((end, '"')) => break (value, &text[end + 1..]),
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'b')) => value.push('\u{8}'),
+                        Some((_, 't')) => value.push('\t'),
+                        Some((_, 'n')) => value.push('\n'),
+                        Some((_, 'f')) => value.push('\u{c}'),
+                        Some((_, 'r')) => value.push('\r'),
+                        Some((_, c @ '"')) | Some((_, c @ '\\')) => value.push(c),
+                        Some((_, u @ 'u')) | Some((_, u @ 'U')) => {
+                            let len = if u == 'u' { 4 } else { 8 };
+                            let hex: String = (0..len)
+                                .filter_map(|_| chars.next().map(|(_, c)| c))
+                                .collect();
+                            let c = u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .filter(|_| hex.len() == len)
+                                .and_then(char::from_u32)
+                                .ok_or("invalid unicode escape")?;
+                            value.push(c);
+                        }
+                        _ => return Err(String::from("unsupported escape")),
+                    },
+                    Some((_, c)) => value.push(c),
+                    None => return Err(String::from("unclosed string")),
+                }
+            }
+        }
+        Some('\'') => {
+            let end = text[1..].find('\'').ok_or("unclosed string")? + 1;
+            (text[1..end].to_owned(), &text[end + 1..])
+        }
+        Some('[') | Some('{') => return Err(String::from("arrays and inline tables are not supported")),
+        Some(_) => {
+            let end = text.find('#').unwrap_or(text.len());
+            let bare = text[..end].trim();
+            let number = bare.replace('_', "");
+            if bare != "true" && bare != "false" && !is_number(&number) {
+                return Err(format!("expected a string, number or boolean, got {:?}", bare));
+            }
+            (number, &text[end..])
+        }
+        None => return Err(String::from("missing value")),
+    };
+    let rest = rest.trim();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(String::from("unexpected text after value"));
+    }
+    Ok(value)
+}
+
+/// A decimal integer or float, which leaves out `inf` and `nan`
+fn is_number(text: &str) -> bool {
+    text.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c))
+        && text.chars().any(|c| c.is_ascii_digit())
+        && text.parse::<f64>().is_ok()
+}
+
+/// A TOML basic string
+fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl Setting for String {
+    fn parse(value: &str) -> Result<String, String> {
+        Ok(value.to_owned())
+    }
+
+    fn show(&self) -> String {
+        quote(self)
+    }
+}
+
+impl Setting for bool {
+    fn parse(value: &str) -> Result<bool, String> {
+        value.parse().map_err(|_| format!("expected true or false, got {:?}", value))
+    }
+
+    fn show(&self) -> String {
+        self.to_string()
+    }
+}
+
+macro_rules! number_setting {
+    ($($ty:ty),*) => {$(
+        impl Setting for $ty {
+            fn parse(value: &str) -> Result<$ty, String> {
+                value.parse().map_err(|_| format!("expected a number, got {:?}", value))
+            }
+
+            fn show(&self) -> String {
+                format!("{:?}", self)
+            }
+        }
+    )*};
+}
+
+number_setting!(u32, usize);
+
+impl Setting for f64 {
+    fn parse(value: &str) -> Result<f64, String> {
+        value
+            .parse()
+            .ok()
+            .filter(|number: &f64| number.is_finite())
+            .ok_or_else(|| format!("expected a number, got {:?}", value))
+    }
+
+    fn show(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+impl Setting for SocketAddr {
+    fn parse(value: &str) -> Result<SocketAddr, String> {
+        value.parse().map_err(|_| format!("expected an address like 0.0.0.0:9999, got {:?}", value))
+    }
+
+    fn show(&self) -> String {
+        format!("\"{}\"", self)
+    }
+}
+
+/// `250ms`, `10s`, `5m` or `1h`, a bare number is seconds
+impl Setting for Duration {
+    fn parse(value: &str) -> Result<Duration, String> {
+        let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+        let amount = value[..split].parse::<u64>();
+        let unit = value[split..].trim();
+        match (amount, unit) {
+            (Ok(ms), "ms") => Ok(Duration::from_millis(ms)),
+            (Ok(secs), "") | (Ok(secs), "s") => Ok(Duration::from_secs(secs)),
+            (Ok(mins), "m") => mins
+                .checked_mul(60)
+                .map(Duration::from_secs)
+                .ok_or_else(|| format!("{:?} is too long", value)),
+            (Ok(hours), "h") => hours
+                .checked_mul(3600)
+                .map(Duration::from_secs)
+                .ok_or_else(|| format!("{:?} is too long", value)),
+            _ => Err(format!("expected a duration like 10s or 250ms, got {:?}", value)),
+        }
+    }
+
+    fn show(&self) -> String {
+        if self.subsec_nanos() == 0 {
+            format!("\"{}s\"", self.as_secs())
+        } else {
+            format!("\"{}ms\"", self.as_millis())
+        }
+    }
+}
+
+/// A number of bytes, as a plain number or with a `KiB`, `MiB` or `GiB`
+/// suffix
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Size(pub usize);
+
+const SIZE_UNITS: [(&str, usize); 3] = [("GiB", 1 << 30), ("MiB", 1 << 20), ("KiB", 1 << 10)];
+
+impl Setting for Size {
+    fn parse(value: &str) -> Result<Size, String> {
+        let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+        let amount = value[..split].parse::<usize>();
+        let unit = value[split..].trim();
+        let scale = match unit {
+            "" | "B" => Some(1),
+            _ => SIZE_UNITS
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+                .map(|(_, scale)| *scale),
+        };
+        match (amount, scale) {
+            (Ok(amount), Some(scale)) => amount
+                .checked_mul(scale)
+                .map(Size)
+                .ok_or_else(|| format!("{:?} is too big", value)),
+            _ => Err(format!("expected a size like 256KiB, got {:?}", value)),
+        }
+    }
+
+    fn show(&self) -> String {
+        for (name, scale) in SIZE_UNITS.iter() {
+            if self.0 >= *scale && self.0.is_multiple_of(*scale) {
+                return format!("\"{}{}\"", self.0 / scale, name);
+            }
+        }
+        self.0.to_string()
+    }
+}
+
+/// How often the servers ping WebSocket clients, and how long a client can
+/// go without answering before it is dropped
+#[derive(Clone, Copy, Debug)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Heartbeat {
+    pub fn from_config(config: &mut Config) -> Heartbeat {
+        let heartbeat = Heartbeat {
+            interval: config.get("heartbeat.interval", Duration::from_secs(5)),
+            timeout: config.get("heartbeat.timeout", Duration::from_secs(10)),
+        };
+        config.check("heartbeat.interval", heartbeat.interval > Duration::from_secs(0), "must be above zero");
+        config.check(
+            "heartbeat.timeout",
+            heartbeat.timeout > heartbeat.interval,
+            "must be longer than heartbeat.interval",
+        );
+        heartbeat
+    }
+}
+
+/// Worker threads when the config does not say, one per CPU
+pub fn default_workers() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config(args: &[&str], env: &[(&str, &str)]) -> Config {
+        let env: HashMap<String, String> = env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        Config::new(
+            "no-such-binary",
+            "TEST",
+            args.iter().map(|arg| arg.to_string()),
+            move |var| env.get(var).cloned(),
+        )
+    }
+
+    #[test]
+    fn parses_the_toml_subset() {
+        let text = r#"
+            # comment
+            top = 1
+            [server]
+            bind = "0.0.0.0:9999"  # trailing comment
+            workers = 4
+            ratio = 2.5
+            [upload]
+            dir = './files # not a comment'
+            max_size = 10_485_760
+            enabled = true
+        "#;
+        let values = parse_toml(text).unwrap();
+        assert_eq!(values["top"], "1");
+        assert_eq!(values["server.bind"], "0.0.0.0:9999");
+        assert_eq!(values["server.workers"], "4");
+        assert_eq!(values["server.ratio"], "2.5");
+        assert_eq!(values["upload.dir"], "./files # not a comment");
+        assert_eq!(values["upload.max_size"], "10485760");
+        assert_eq!(values["upload.enabled"], "true");
+
+        assert!(parse_toml("a = [1, 2]").is_err());
+        assert!(parse_toml("a = 1\na = 2").is_err());
+        assert!(parse_toml("a = \"open").is_err());
+        assert!(parse_toml("a = bare").is_err());
+        assert!(parse_toml("a = inf").is_err());
+        assert!(parse_toml("a = nan").is_err());
+        assert!(parse_toml("[server").is_err());
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let mut config = config(
+            &["--server.workers=8", "--heartbeat.interval", "2s"],
+            &[("TEST_SERVER_WORKERS", "4"), ("TEST_SERVER_BIND", "127.0.0.1:1234")],
+        );
+        assert_eq!(config.get("server.workers", 1usize), 8);
+        assert_eq!(config.get("heartbeat.interval", Duration::from_secs(5)), Duration::from_secs(2));
+        assert_eq!(
+            config.get("server.bind", "0.0.0.0:9999".parse::<SocketAddr>().unwrap()),
+            "127.0.0.1:1234".parse().unwrap()
+        );
+        assert_eq!(config.get("server.max_size", Size(1024)), Size(1024));
+        assert!(config.finish().is_ok());
+    }
+
+    #[test]
+    fn aliases_are_read_after_the_derived_name() {
+        let mut config = config(&[], &[("OLD_GRACE", "30")]).alias("session.grace", "OLD_GRACE");
+        assert_eq!(config.get("session.grace", Duration::from_secs(60)), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn reports_bad_values_and_unknown_flags() {
+        let mut config = config(&["--server.workers=many", "--server.typo=1"], &[]);
+        assert_eq!(config.get("server.workers", 2usize), 2);
+        assert!(config.finish().is_err());
+        assert_eq!(config.errors.len(), 2);
+    }
+
+    #[test]
+    fn parses_durations_and_sizes() {
+        assert_eq!(Duration::parse("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(Duration::parse("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(Duration::parse("5m"), Ok(Duration::from_secs(300)));
+        assert!(Duration::parse("soon").is_err());
+        assert!(Duration::parse("18446744073709551615h").is_err());
+        assert_eq!(Size::parse("256KiB"), Ok(Size(256 * 1024)));
+        assert_eq!(Size::parse("10mib"), Ok(Size(10 << 20)));
+        assert_eq!(Size::parse("100"), Ok(Size(100)));
+        assert!(Size::parse("10MB").is_err());
+        assert_eq!(Size(10 << 20).show(), "\"10MiB\"");
+        assert_eq!(Duration::from_millis(1500).show(), "\"1500ms\"");
+    }
+
+    #[test]
+    fn strings_are_written_back_as_toml() {
+        let text = "tab\there \"quoted\" back\\slash \u{1} caf\u{e9}";
+        let shown = String::from(text).show();
+        assert_eq!(shown, r#""tab\there \"quoted\" back\\slash \u0001 café""#);
+        let values = parse_toml(&format!("a = {}", shown)).unwrap();
+        assert_eq!(values["a"], text);
+        assert!(f64::parse("inf").is_err());
+    }
+}
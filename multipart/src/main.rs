@@ -1,14 +1,25 @@
 use actix_multipart::Multipart;
 use actix_web::web;
-use actix_web::{App, Error, HttpResponse, HttpServer, Responder};
+use actix_web::{App, error, Error, HttpResponse, HttpServer, Responder};
 use futures::{StreamExt, TryStreamExt};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::io::Write;
 
-async fn save_file(mut payload: Multipart) -> Result<HttpResponse, Error> {
+use config::Config;
+
+/// Where uploaded files go
+struct UploadDir(PathBuf);
+
+async fn save_file(mut payload: Multipart, dir: web::Data<UploadDir>) -> Result<HttpResponse, Error> {
     while let Ok(Some(mut field)) = payload.try_next().await {
         let content_type = field.content_disposition().unwrap();
         let filename = content_type.get_filename().unwrap();
-        let filepath = format!("./tmp/{}", filename);
+        // Only the last part of the name, so the file stays in the upload dir
+        let filename = Path::new(filename)
+            .file_name()
+            .ok_or_else(|| error::ErrorBadRequest("bad file name"))?;
+        let filepath = dir.0.join(filename);
         // Creating file using std::fs::File::create is blocking,
         // so we hand this work to thread pool
         let mut f = web::block(|| std::fs::File::create(filepath))
@@ -39,14 +50,24 @@ async fn index() -> impl Responder {
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| {
-        App::new().service(
+    let mut config = Config::load("multipart", "MULTIPART");
+    let bind = config.get("server.bind", SocketAddr::from(([0, 0, 0, 0], 9999)));
+    let dir = config.get("upload.dir", String::from("./tmp"));
+    config.finish()?;
+    if config.print {
+        print!("{}", config);
+        return Ok(());
+    }
+
+    let dir = web::Data::new(UploadDir(PathBuf::from(dir)));
+    HttpServer::new(move || {
+        App::new().app_data(dir.clone()).service(
             web::resource("/")
                 .route(web::get().to(index))
                 .route(web::post().to(save_file)),
         )
     })
-    .bind(("0.0.0.0", 9999))?
+    .bind(bind)?
     .run()
     .await
 }
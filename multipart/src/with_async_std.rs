@@ -2,9 +2,16 @@ use actix_multipart::Multipart;
 use actix_web::web;
 use actix_web::{App, error, Error, HttpResponse, HttpServer, Responder};
 use futures::{StreamExt, TryStreamExt};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use async_std::prelude::*;
 
-async fn save_file(mut payload: Multipart) -> Result<HttpResponse, Error> {
+use config::Config;
+
+/// Where uploaded files go
+struct UploadDir(PathBuf);
+
+async fn save_file(mut payload: Multipart, dir: web::Data<UploadDir>) -> Result<HttpResponse, Error> {
     while let Ok(Some(mut field)) = TryStreamExt::try_next(&mut payload).await {
         let content_disposition = field
             .content_disposition()
@@ -15,7 +22,11 @@ async fn save_file(mut payload: Multipart) -> Result<HttpResponse, Error> {
             .ok_or_else(|| error::ParseError::Incomplete)?;
 
 
-        let filepath = format!("./tmp/{}", filename);
+        // Only the last part of the name, so the file stays in the upload dir
+        let filename = Path::new(filename)
+            .file_name()
+            .ok_or_else(|| error::ErrorBadRequest("bad file name"))?;
+        let filepath = dir.0.join(filename);
 
         let mut f = async_std::fs::File::create(filepath).await?;
 
@@ -44,14 +55,24 @@ async fn index() -> impl Responder {
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| {
-        App::new().service(
+    let mut config = Config::load("with-async-std", "MULTIPART");
+    let bind = config.get("server.bind", SocketAddr::from(([0, 0, 0, 0], 9999)));
+    let dir = config.get("upload.dir", String::from("./tmp"));
+    config.finish()?;
+    if config.print {
+        print!("{}", config);
+        return Ok(());
+    }
+
+    let dir = web::Data::new(UploadDir(PathBuf::from(dir)));
+    HttpServer::new(move || {
+        App::new().app_data(dir.clone()).service(
             web::resource("/")
                 .route(web::get().to(index))
                 .route(web::post().to(save_file)),
         )
     })
-    .bind(("0.0.0.0", 9999))?
+    .bind(bind)?
     .run()
     .await
 }
@@ -0,0 +1,272 @@
+//! A tiny pub/sub broker for running `ws-chat-server` nodes as a cluster
+//! without Redis. It speaks just enough of the Redis protocol for the chat
+//! servers: PING, SUBSCRIBE, UNSUBSCRIBE and PUBLISH.
+
+use actix::io::{SinkWrite, WriteHandler};
+use actix::prelude::*;
+use actix_codec::Framed;
+use actix_rt::net::{TcpListener, TcpStream};
+use bytes::Bytes;
+use futures::sink::{Buffer, SinkExt};
+use futures::stream::{SplitSink, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+
+mod resp;
+
+use config::Config;
+use resp::{Resp, RespCodec, WRITE_QUEUE};
+
+/// Something for a connection to write out
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Push(Resp);
+
+#[derive(Message)]
+#[rtype(result = "usize")]
+struct Register(Recipient<Push>);
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Unregister(usize);
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Subscribe {
+    id: usize,
+    channel: Bytes,
+    subscribe: bool,
+}
+
+/// Hand a message to the subscribers of a channel, returns how many got it
+#[derive(Message)]
+#[rtype(result = "usize")]
+struct Publish {
+    channel: Bytes,
+    message: Bytes,
+}
+
+#[derive(Default)]
+struct Broker {
+    next_id: usize,
+    peers: HashMap<usize, Recipient<Push>>,
+    channels: HashMap<Bytes, HashSet<usize>>,
+}
+
+impl Actor for Broker {
+    type Context = Context<Self>;
+}
+
+impl Handler<Register> for Broker {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Register, _: &mut Self::Context) -> Self::Result {
+        self.next_id += 1;
+        self.peers.insert(self.next_id, msg.0);
+        self.next_id
+    }
+}
+
+impl Handler<Unregister> for Broker {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unregister, _: &mut Self::Context) -> Self::Result {
+        self.peers.remove(&msg.0);
+        for subscribers in self.channels.values_mut() {
+            subscribers.remove(&msg.0);
+        }
+        self.channels.retain(|_, subscribers| !subscribers.is_empty());
+    }
+}
+
+impl Handler<Subscribe> for Broker {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Self::Context) -> Self::Result {
+        if msg.subscribe {
+            self.channels.entry(msg.channel).or_default().insert(msg.id);
+        } else if let Some(subscribers) = self.channels.get_mut(&msg.channel) {
+            subscribers.remove(&msg.id);
+            if subscribers.is_empty() {
+                self.channels.remove(&msg.channel);
+            }
+        }
+    }
+}
+
+impl Handler<Publish> for Broker {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Publish, _: &mut Self::Context) -> Self::Result {
+        let subscribers = match self.channels.get(&msg.channel) {
+            Some(subscribers) => subscribers,
+            None => return 0,
+        };
+        let mut delivered = 0;
+        for id in subscribers {
+            if let Some(peer) = self.peers.get(id) {
+                let push = Resp::array(vec![
+                    Bytes::from_static(b"message"),
+                    msg.channel.clone(),
+                    msg.message.clone(),
+                ]);
+                if peer.do_send(Push(push)).is_ok() {
+                    delivered += 1;
+                }
+            }
+        }
+        delivered
+    }
+}
+
+/// A split sink holds a single item and `SinkWrite` does not wait for it to
+/// go out, so writes queue up in a buffer in front of it
+type Sink = SinkWrite<Resp, Buffer<SplitSink<Framed<TcpStream, RespCodec>, Resp>, Resp>>;
+
+/// A client connection
+struct Peer {
+    id: usize,
+    sink: Sink,
+    broker: Addr<Broker>,
+    /// Channels this connection is subscribed to
+    channels: HashSet<Bytes>,
+}
+
+impl Actor for Peer {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.broker
+            .send(Register(ctx.address().recipient()))
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(id) => act.id = id,
+                    _ => ctx.stop(),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        self.broker.do_send(Unregister(self.id));
+        Running::Stop
+    }
+}
+
+impl Handler<Push> for Peer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Push, _: &mut Self::Context) -> Self::Result {
+        let _ = self.sink.write(msg.0);
+    }
+}
+
+impl Peer {
+    fn subscribe(&mut self, channels: &[Bytes], subscribe: bool) {
+        let kind: &'static [u8] = if subscribe { b"subscribe" } else { b"unsubscribe" };
+        for channel in channels {
+            if subscribe {
+                self.channels.insert(channel.clone());
+            } else {
+                self.channels.remove(channel);
+            }
+            self.broker.do_send(Subscribe {
+                id: self.id,
+                channel: channel.clone(),
+                subscribe,
+            });
+            let _ = self.sink.write(Resp::Array(Some(vec![
+                Resp::Bulk(Some(Bytes::from_static(kind))),
+                Resp::Bulk(Some(channel.clone())),
+                Resp::Integer(self.channels.len() as i64),
+            ])));
+        }
+    }
+
+    fn command(&mut self, parts: Vec<Bytes>, ctx: &mut Context<Self>) {
+        let name = String::from_utf8_lossy(&parts[0]).to_ascii_uppercase();
+        match (name.as_str(), &parts[1..]) {
+            ("PING", _) => {
+                let _ = self.sink.write(Resp::Simple(String::from("PONG")));
+            }
+            ("SUBSCRIBE", channels) if !channels.is_empty() => self.subscribe(channels, true),
+            ("UNSUBSCRIBE", []) => {
+                let channels: Vec<Bytes> = self.channels.iter().cloned().collect();
+                self.subscribe(&channels, false);
+            }
+            ("UNSUBSCRIBE", channels) => self.subscribe(channels, false),
+            ("PUBLISH", [channel, message]) => {
+                let publish = Publish {
+                    channel: channel.clone(),
+                    message: message.clone(),
+                };
+                self.broker
+                    .send(publish)
+                    .into_actor(self)
+                    .then(|res, act, _| {
+                        let delivered = res.unwrap_or(0);
+                        let _ = act.sink.write(Resp::Integer(delivered as i64));
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            _ => {
+                let error = format!("ERR unknown command or wrong number of arguments for '{}'", name);
+                let _ = self.sink.write(Resp::Error(error));
+            }
+        }
+    }
+}
+
+impl StreamHandler<Result<Resp, io::Error>> for Peer {
+    fn handle(&mut self, msg: Result<Resp, io::Error>, ctx: &mut Self::Context) {
+        let parts = match msg.map(Resp::into_parts) {
+            Ok(Some(ref parts)) if parts.is_empty() => return,
+            Ok(Some(parts)) => parts,
+            Ok(None) => {
+                let _ = self.sink.write(Resp::Error(String::from("ERR expected an array of strings")));
+                return;
+            }
+            Err(e) => {
+                println!("Dropping relay client: {}", e);
+                return ctx.stop();
+            }
+        };
+        self.command(parts, ctx);
+    }
+}
+
+impl WriteHandler<io::Error> for Peer {}
+
+#[actix_rt::main]
+async fn main() -> io::Result<()> {
+    let mut config = Config::load("ws-chat-relay", "CHAT_RELAY").alias("server.bind", "CHAT_RELAY_ADDR");
+    let addr = config.get("server.bind", SocketAddr::from(([127, 0, 0, 1], 6380)));
+    config.finish()?;
+    if config.print {
+        print!("{}", config);
+        return Ok(());
+    }
+    let mut listener = TcpListener::bind(addr).await?;
+    println!("Chat relay listening on {}", addr);
+
+    let broker = Broker::default().start();
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        println!("Relay client connected from {}", peer);
+        let broker = broker.clone();
+        Peer::create(move |ctx| {
+            let (sink, stream) = Framed::new(stream, RespCodec).split();
+            Peer::add_stream(stream, ctx);
+            Peer {
+                id: 0,
+                sink: SinkWrite::new(sink.buffer(WRITE_QUEUE), ctx),
+                broker,
+                channels: HashSet::new(),
+            }
+        });
+    }
+}
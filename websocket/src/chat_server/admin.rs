@@ -0,0 +1,137 @@
+use actix::Addr;
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use serde::Deserialize;
+
+use crate::auth::{bearer, same, unauthorized};
+use config::Config;
+use crate::server;
+
+/// The token admin requests carry as `Authorization: Bearer <token>`. The
+/// admin API is off while there is none.
+#[derive(Clone)]
+pub struct AdminToken(pub Option<String>);
+
+impl AdminToken {
+    pub fn from_config(config: &mut Config) -> AdminToken {
+        AdminToken(config.secret("admin.token"))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Broadcast {
+    pub text: String,
+}
+
+#[derive(Deserialize)]
+pub struct DisconnectQuery {
+    pub reason: Option<String>,
+}
+
+/// Turn away requests without the admin token
+fn authorize(req: &HttpRequest, token: &AdminToken) -> Result<(), Error> {
+    let expected = match token.0 {
+        Some(ref token) => token,
+        None => return Err(error::ErrorForbidden("admin API is disabled")),
+    };
+    match bearer(req) {
+        Some(given) if same(given.as_bytes(), expected.as_bytes()) => Ok(()),
+        _ => Err(unauthorized("admin token required")),
+    }
+}
+
+/// Every room with its member count
+pub async fn rooms(
+    req: HttpRequest,
+    token: web::Data<AdminToken>,
+    srv: web::Data<Addr<server::ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    authorize(&req, &token)?;
+    let rooms = srv
+        .send(server::AdminRooms)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(rooms))
+}
+
+/// The sessions in a room
+pub async fn members(
+    req: HttpRequest,
+    room: web::Path<String>,
+    token: web::Data<AdminToken>,
+    srv: web::Data<Addr<server::ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    authorize(&req, &token)?;
+    let members = srv
+        .send(server::AdminMembers {
+            room: room.into_inner(),
+        })
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .map_err(error::ErrorNotFound)?;
+    Ok(HttpResponse::Ok().json(members))
+}
+
+/// Post a message from the server into a room, answering with its stamp
+pub async fn broadcast(
+    req: HttpRequest,
+    room: web::Path<String>,
+    body: web::Json<Broadcast>,
+    token: web::Data<AdminToken>,
+    srv: web::Data<Addr<server::ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    authorize(&req, &token)?;
+    let stamp = srv
+        .send(server::SystemBroadcast {
+            room: room.into_inner(),
+            text: body.into_inner().text,
+        })
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .map_err(error::ErrorNotFound)?;
+    Ok(HttpResponse::Ok().json(stamp))
+}
+
+/// Close a session for good
+pub async fn disconnect(
+    req: HttpRequest,
+    id: web::Path<usize>,
+    query: web::Query<DisconnectQuery>,
+    token: web::Data<AdminToken>,
+    srv: web::Data<Addr<server::ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    authorize(&req, &token)?;
+    let reason = query
+        .into_inner()
+        .reason
+        .unwrap_or_else(|| String::from("disconnected by an admin"));
+    srv.send(server::ForceDisconnect {
+        id: id.into_inner(),
+        reason,
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)?
+    .map_err(error::ErrorNotFound)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Turn everyone out of a room and remove it with its history. A room that
+/// does not exist answers 404, permanent rooms, `Main` and the ones in
+/// `rooms.txt`, answer 409.
+pub async fn close_room(
+    req: HttpRequest,
+    room: web::Path<String>,
+    token: web::Data<AdminToken>,
+    srv: web::Data<Addr<server::ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    authorize(&req, &token)?;
+    srv.send(server::CloseRoom {
+        room: room.into_inner(),
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)?
+    .map_err(|e| match e {
+        server::CloseRefused::NoSuchRoom(_) => error::ErrorNotFound(e),
+        server::CloseRefused::Permanent(_) => error::ErrorConflict(e),
+    })?;
+    Ok(HttpResponse::NoContent().finish())
+}
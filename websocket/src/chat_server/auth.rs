@@ -0,0 +1,123 @@
+use actix_web::{error, http::header, Error, HttpMessage, HttpRequest, HttpResponse};
+use std::collections::HashMap;
+
+use crate::protocol::query_param;
+
+/// Optional list of users allowed to chat, one `name token` pair per line.
+/// Once there are any, connecting takes one of the tokens.
+pub const USERS_FILE: &str = "./users.txt";
+/// Cookie a browser can carry the token in
+const TOKEN_COOKIE: &str = "chat_token";
+
+/// Who can connect, by token
+#[derive(Clone, Default)]
+pub struct Users {
+    tokens: HashMap<String, String>,
+    /// Pages other than our own that may use the token cookie
+    origins: Vec<String>,
+}
+
+impl Users {
+    /// Read `name token` lines, skipping the ones that do not fit
+    pub fn parse(lines: Vec<String>) -> Users {
+        let mut tokens = HashMap::new();
+        for line in lines {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(name), Some(token), None) => {
+                    tokens.insert(token.to_owned(), name.to_owned());
+                }
+                _ => println!("Ignoring malformed line in the users file"),
+            }
+        }
+        Users {
+            tokens,
+            origins: Vec::new(),
+        }
+    }
+
+    /// Let pages from these origins, like `https://chat.example.com`, connect
+    /// with the token cookie. Pages served by this server always can.
+    pub fn allow_origins(mut self, origins: Vec<String>) -> Users {
+        self.origins = origins;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// The user a request is from. Without any users everyone gets in
+    /// anonymously.
+    pub fn authenticate(&self, req: &HttpRequest) -> Result<Option<String>, Error> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let (given, from_cookie) = token(req).ok_or_else(|| unauthorized("token required"))?;
+        // A browser sends the cookie along whatever page opened the connection
+        if from_cookie && !self.origin_allowed(req) {
+            return Err(error::ErrorForbidden("origin not allowed"));
+        }
+        // Check every token so the time taken does not tell which came close
+        let mut user = None;
+        for (token, name) in &self.tokens {
+            if same(given.as_bytes(), token.as_bytes()) {
+                user = Some(name.clone());
+            }
+        }
+        user.map(Some).ok_or_else(|| unauthorized("invalid token"))
+    }
+
+    /// Whether the page a request comes from may use the cookie. Browsers
+    /// name it in `Origin` on every cross-site request, so a request
+    /// without one is from the same site or not from a browser.
+    fn origin_allowed(&self, req: &HttpRequest) -> bool {
+        let origin = match req.headers().get(header::ORIGIN).map(|value| value.to_str()) {
+            None => return true,
+            Some(Ok(origin)) => origin,
+            Some(Err(_)) => return false,
+        };
+        let host = req.headers().get(header::HOST).and_then(|value| value.to_str().ok());
+        let authority = origin
+            .strip_prefix("https://")
+            .or_else(|| origin.strip_prefix("http://"));
+        (host.is_some() && authority == host) || self.origins.iter().any(|allowed| allowed == origin)
+    }
+}
+
+/// The bearer token of a request, and whether it came from the cookie.
+///
+/// The `Authorization` header is the way to send it. Browsers can not set
+/// headers on a WebSocket or an EventSource, so they get the `chat_token`
+/// cookie, or as a last resort the `token` query parameter, which ends up
+/// in access logs and browser history.
+fn token(req: &HttpRequest) -> Option<(String, bool)> {
+    if let Some(token) = bearer(req).or_else(|| query_param(req.query_string(), "token")) {
+        return Some((token.to_owned(), false));
+    }
+    req.cookie(TOKEN_COOKIE).map(|cookie| (cookie.value().to_owned(), true))
+}
+
+/// The bearer token of a request from its `Authorization` header
+pub fn bearer(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// A 401 asking for a bearer token
+pub fn unauthorized(message: &'static str) -> Error {
+    error::InternalError::from_response(
+        message,
+        HttpResponse::Unauthorized()
+            .header(header::WWW_AUTHENTICATE, "Bearer")
+            .body(message),
+    )
+    .into()
+}
+
+/// Compare without giving away how much of a token was right
+pub fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
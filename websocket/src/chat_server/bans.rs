@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::server::same_name;
+
+pub const BANS_FILE: &str = "./bans.json";
+
+/// A ban on a nickname, an IP address or both
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Ban {
+    pub name: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl Ban {
+    pub fn matches(&self, name: Option<&str>, ip: Option<&str>) -> bool {
+        let by_name = match (&self.name, name) {
+            (Some(banned), Some(name)) => same_name(banned, name),
+            _ => false,
+        };
+        let by_ip = match (&self.ip, ip) {
+            (Some(banned), Some(ip)) => banned == ip,
+            _ => false,
+        };
+        by_name || by_ip
+    }
+}
+
+/// Room bans, written back to disk on every change so they survive restarts
+pub struct Bans {
+    path: PathBuf,
+    rooms: HashMap<String, Vec<Ban>>,
+}
+
+impl Bans {
+    pub fn load<P: Into<PathBuf>>(path: P) -> Bans {
+        let path = path.into();
+        let rooms = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                println!("Ignoring malformed bans in {:?}: {}", path, e);
+                HashMap::new()
+            }),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                println!("Can not read bans from {:?}: {}", path, e);
+                HashMap::new()
+            }
+        };
+        Bans { path, rooms }
+    }
+
+    pub fn is_banned(&self, room: &str, name: Option<&str>, ip: Option<&str>) -> bool {
+        self.rooms
+            .get(room)
+            .is_some_and(|bans| bans.iter().any(|ban| ban.matches(name, ip)))
+    }
+
+    pub fn add(&mut self, room: &str, ban: Ban) {
+        let bans = self.rooms.entry(room.to_owned()).or_default();
+        if !bans.contains(&ban) {
+            bans.push(ban);
+            self.save();
+        }
+    }
+
+    /// Every room with bans, and its bans
+    pub fn rooms(&self) -> impl Iterator<Item = (&String, &Vec<Ban>)> {
+        self.rooms.iter()
+    }
+
+    /// Lift every ban of the room on `target`, which is a name or an IP.
+    /// Returns false if there was none.
+    pub fn remove(&mut self, room: &str, target: &str) -> bool {
+        let removed = match self.rooms.get_mut(room) {
+            Some(bans) => {
+                let before = bans.len();
+                bans.retain(|ban| !ban.matches(Some(target), Some(target)));
+                bans.len() != before
+            }
+            None => false,
+        };
+        if removed {
+            self.rooms.retain(|_, bans| !bans.is_empty());
+            self.save();
+        }
+        removed
+    }
+
+    pub fn save(&self) {
+        let res = serde_json::to_string_pretty(&self.rooms)
+            .map_err(io::Error::from)
+            .and_then(|json| fs::write(&self.path, json));
+        if let Err(e) = res {
+            println!("Can not write bans to {:?}: {}", self.path, e);
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use actix::io::{SinkWrite, WriteHandler};
+use actix::prelude::*;
+use actix_codec::Framed;
+use actix_rt::net::TcpStream;
+use bytes::Bytes;
+use futures::sink::{Buffer, SinkExt};
+use futures::stream::{SplitSink, StreamExt};
+use serde::{Deserialize, Serialize};
+use actix_rt::blocking::BlockingError;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+
+use crate::bans::Ban;
+use config::Config;
+use crate::protocol::Event;
+use crate::resp::{Resp, RespCodec, WRITE_QUEUE};
+
+/// Wait between attempts to reach the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(3);
+
+/// Where the nodes of a cluster meet
+#[derive(Clone, Debug)]
+pub struct ClusterConfig {
+    /// `host:port` of a Redis server or a `ws-chat-relay`
+    pub broker: String,
+    pub channel: String,
+}
+
+impl ClusterConfig {
+    /// Clustering is off unless `cluster.broker` names a broker, as
+    /// `host:port` or `redis://host:port`. `cluster.channel` picks the
+    /// channel, "chat" by default.
+    pub fn from_config(config: &mut Config) -> Option<ClusterConfig> {
+        let broker = config.optional::<String>("cluster.broker");
+        let channel = config.get("cluster.channel", String::from("chat"));
+        let broker = broker?;
+        let broker = broker.trim_start_matches("redis://").trim_end_matches('/');
+        Some(ClusterConfig {
+            broker: broker.to_owned(),
+            channel,
+        })
+    }
+}
+
+/// What one node passes on to the others
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Relay {
+    /// Node the update comes from, so it does not take its own back
+    pub node: String,
+    #[serde(flatten)]
+    pub update: Update,
+}
+
+/// Room state is kept by every node, these keep it the same everywhere
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Update {
+    /// An event for the local members of `rooms`
+    Event { rooms: Vec<String>, event: Event },
+    /// Who may join the room
+    Room {
+        room: String,
+        private: bool,
+        password: Option<String>,
+    },
+    Ban { room: String, bans: Vec<Ban> },
+    Unban { room: String, target: String },
+    /// A node (re)joined, everybody tells it how their rooms are set up
+    Sync,
+}
+
+/// Send an event to the other nodes
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Publish(pub Relay);
+
+/// An update from another node
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Relayed(pub Relay);
+
+/// The subscription to the other nodes is up, anything they said before
+/// was missed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Subscribed;
+
+enum Role {
+    Publisher,
+    /// Hands what the other nodes publish to the chat server
+    Subscriber(Recipient<Relayed>, Recipient<Subscribed>),
+}
+
+/// A split sink holds a single item and `SinkWrite` does not wait for it to
+/// go out, so writes queue up in a buffer in front of it
+type Sink = SinkWrite<Resp, Buffer<SplitSink<Framed<TcpStream, RespCodec>, Resp>, Resp>>;
+
+/// A connection to the broker. Redis wants subscribing and publishing on
+/// separate connections, so every node keeps one of each.
+pub struct Link {
+    config: ClusterConfig,
+    role: Role,
+    node: String,
+    sink: Option<Sink>,
+    stream: Option<SpawnHandle>,
+}
+
+impl Link {
+    pub fn publisher(config: ClusterConfig, node: String) -> Link {
+        Link {
+            config,
+            role: Role::Publisher,
+            node,
+            sink: None,
+            stream: None,
+        }
+    }
+
+    pub fn subscriber(
+        config: ClusterConfig,
+        node: String,
+        server: Recipient<Relayed>,
+        subscribed: Recipient<Subscribed>,
+    ) -> Link {
+        Link {
+            config,
+            role: Role::Subscriber(server, subscribed),
+            node,
+            sink: None,
+            stream: None,
+        }
+    }
+
+    fn connect(&mut self, ctx: &mut Context<Self>) {
+        let broker = self.config.broker.clone();
+        async move {
+            // Name lookups block, keep them off the actor's thread
+            let addrs = actix_rt::blocking::run(move || broker.to_socket_addrs())
+                .await
+                .map_err(|e| match e {
+                    BlockingError::Error(e) => e,
+                    BlockingError::Canceled => io::Error::other("lookup canceled"),
+                })?;
+            let mut last = io::Error::new(io::ErrorKind::NotFound, "no address");
+            for addr in addrs {
+                match TcpStream::connect(addr).await {
+                    Ok(stream) => return Ok(stream),
+                    Err(e) => last = e,
+                }
+            }
+            Err(last)
+        }
+        .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(stream) => {
+                        println!("Connected to cluster broker {}", act.config.broker);
+                        let (sink, stream) = Framed::new(stream, RespCodec).split();
+                        act.stream = Some(ctx.add_stream(stream));
+                        let mut sink = SinkWrite::new(sink.buffer(WRITE_QUEUE), ctx);
+                        if let Role::Subscriber(..) = act.role {
+                            let _ = sink.write(Resp::array(vec![
+                                String::from("SUBSCRIBE"),
+                                act.config.channel.clone(),
+                            ]));
+                        }
+                        act.sink = Some(sink);
+                        if let Role::Publisher = act.role {
+                            // Updates made while we were away are lost, ask again
+                            let node = act.node.clone();
+                            act.publish(&Relay { node, update: Update::Sync });
+                        }
+                    }
+                    Err(e) => {
+                        println!("Can not reach cluster broker {}: {}", act.config.broker, e);
+                        ctx.run_later(RECONNECT_DELAY, |act, ctx| act.connect(ctx));
+                    }
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn publish(&mut self, relay: &Relay) {
+        let sink = match self.sink {
+            Some(ref mut sink) => sink,
+            None => return println!("Not connected to the cluster, dropping an update"),
+        };
+        let payload = serde_json::to_string(relay).expect("relays always serialize");
+        let _ = sink.write(Resp::array(vec![
+            String::from("PUBLISH"),
+            self.config.channel.clone(),
+            payload,
+        ]));
+    }
+
+    /// Drop a broken connection and try again in a bit
+    fn reconnect(&mut self, ctx: &mut Context<Self>) {
+        let sink = self.sink.take();
+        let stream = self.stream.take();
+        if sink.is_none() && stream.is_none() {
+            // Already on it
+            return;
+        }
+        if let Some(sink) = sink {
+            ctx.cancel_future(sink.handle());
+        }
+        if let Some(stream) = stream {
+            ctx.cancel_future(stream);
+        }
+        ctx.run_later(RECONNECT_DELAY, |act, ctx| act.connect(ctx));
+    }
+}
+
+impl Actor for Link {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.connect(ctx);
+    }
+}
+
+impl Handler<Publish> for Link {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, _: &mut Self::Context) -> Self::Result {
+        self.publish(&msg.0);
+    }
+}
+
+impl StreamHandler<Result<Resp, io::Error>> for Link {
+    fn handle(&mut self, msg: Result<Resp, io::Error>, ctx: &mut Self::Context) {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                println!("Cluster broker connection failed: {}", e);
+                return self.reconnect(ctx);
+            }
+        };
+        let (server, subscribed) = match self.role {
+            Role::Subscriber(ref server, ref subscribed) => (server, subscribed),
+            // Publish replies just count the subscribers
+            Role::Publisher => return,
+        };
+        // Confirmations look like ["subscribe", channel, count]
+        if let Resp::Array(Some(ref items)) = msg {
+            if items.first() == Some(&Resp::Bulk(Some(Bytes::from_static(b"subscribe")))) {
+                let _ = subscribed.do_send(Subscribed);
+                return;
+            }
+        }
+        // Pushes look like ["message", channel, payload]
+        let parts = match msg.into_parts() {
+            Some(parts) => parts,
+            None => return,
+        };
+        if parts.len() != 3 || &parts[0][..] != b"message" {
+            return;
+        }
+        match serde_json::from_slice::<Relay>(&parts[2]) {
+            Ok(relay) if relay.node != self.node => {
+                let _ = server.do_send(Relayed(relay));
+            }
+            Ok(_) => (),
+            Err(e) => println!("Ignoring malformed cluster update: {}", e),
+        }
+    }
+
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        println!("Lost the cluster broker {}, reconnecting", self.config.broker);
+        self.reconnect(ctx);
+    }
+}
+
+impl WriteHandler<io::Error> for Link {
+    fn error(&mut self, err: io::Error, ctx: &mut Self::Context) -> Running {
+        println!("Cluster broker connection failed: {}", err);
+        self.reconnect(ctx);
+        Running::Continue
+    }
+
+    fn finished(&mut self, _: &mut Self::Context) {}
+}
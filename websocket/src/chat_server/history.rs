@@ -0,0 +1,241 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use crate::protocol::{Event, Stamp};
+
+pub const HISTORY_DIR: &str = "./history";
+pub const HISTORY_REPLAY: usize = 20;
+
+/// Append-only chat history, one log file per room.
+///
+/// Every line is a JSON encoded `Event`. The last `replay` events of every
+/// room touched so far are kept in memory, so replaying on join does not have
+/// to go back to disk. So is the sequence number of its last message, which
+/// does not depend on how many events are replayed.
+pub struct History {
+    dir: PathBuf,
+    replay: usize,
+    recent: HashMap<String, VecDeque<Event>>,
+    seqs: HashMap<String, u64>,
+}
+
+impl History {
+    pub fn new<P: Into<PathBuf>>(dir: P, replay: usize) -> Self {
+        let dir = dir.into();
+        if let Err(e) = fs::create_dir_all(&dir) {
+            println!("Can not create history dir {:?}: {}", dir, e);
+        }
+        Self {
+            dir,
+            replay,
+            recent: HashMap::new(),
+            seqs: HashMap::new(),
+        }
+    }
+
+    /// Append an event to the room log
+    pub fn append(&mut self, room: &str, event: &Event) {
+        // Read the log before writing to it, or the event is loaded twice
+        self.recent(room);
+        let line = event.to_json();
+        let path = self.path(room);
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut f| writeln!(f, "{}", line));
+        if let Err(e) = res {
+            println!("Can not write history to {:?}: {}", path, e);
+        }
+
+        let replay = self.replay;
+        if let Some(stamp) = event.stamp() {
+            let seq = self.seqs.entry(room.to_owned()).or_default();
+            *seq = (*seq).max(stamp.seq);
+        }
+        let recent = self.recent(room);
+        recent.push_back(event.clone());
+        while recent.len() > replay {
+            recent.pop_front();
+        }
+    }
+
+    /// The last events of the room, oldest first
+    pub fn replay(&mut self, room: &str) -> Vec<Event> {
+        self.recent(room).iter().cloned().collect()
+    }
+
+    /// Sequence number of the last message logged for the room
+    pub fn last_seq(&mut self, room: &str) -> u64 {
+        self.recent(room);
+        self.seqs.get(room).copied().unwrap_or_default()
+    }
+
+    /// Get the logs of every room written so far onto the disk
+    pub fn sync(&self) {
+        for room in self.recent.keys() {
+            let path = self.path(room);
+            match File::open(&path).and_then(|f| f.sync_all()) {
+                Ok(()) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => println!("Can not sync history {:?}: {}", path, e),
+            }
+        }
+    }
+
+    /// Drop the in-memory tail of a room, the log on disk is kept
+    pub fn forget(&mut self, room: &str) {
+        self.recent.remove(room);
+        self.seqs.remove(room);
+    }
+
+    /// Drop the room for good, its log on disk as well
+    pub fn delete(&mut self, room: &str) {
+        self.recent.remove(room);
+        self.seqs.remove(room);
+        let path = self.path(room);
+        match fs::remove_file(&path) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => println!("Can not remove history {:?}: {}", path, e),
+        }
+    }
+
+    fn recent(&mut self, room: &str) -> &mut VecDeque<Event> {
+        if !self.recent.contains_key(room) {
+            let (events, seq) = match self.load(room) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        println!("Can not read history of {}: {}", room, e);
+                    }
+                    (VecDeque::new(), 0)
+                }
+            };
+            self.recent.insert(room.to_owned(), events);
+            self.seqs.insert(room.to_owned(), seq);
+        }
+        self.recent.get_mut(room).unwrap()
+    }
+
+    /// The last events of the room log and the highest sequence number in it
+    fn load(&self, room: &str) -> io::Result<(VecDeque<Event>, u64)> {
+        let f = File::open(self.path(room))?;
+        let mut events = VecDeque::with_capacity(self.replay + 1);
+        let mut seq = 0;
+        for line in BufReader::new(f).lines() {
+            let line = line?;
+            // Logs written before the JSON format hold bare text lines
+            let event = serde_json::from_str(&line).unwrap_or_else(|_| Event::Message {
+                room: room.to_owned(),
+                from: None,
+                text: line,
+                stamp: Stamp::default(),
+            });
+            if let Some(stamp) = event.stamp() {
+                seq = seq.max(stamp.seq);
+            }
+            events.push_back(event);
+            if events.len() > self.replay {
+                events.pop_front();
+            }
+        }
+        Ok((events, seq))
+    }
+
+    fn path(&self, room: &str) -> PathBuf {
+        // Room names come straight from clients, escape anything that
+        // could leave the history dir
+        let mut name = String::with_capacity(room.len() + 4);
+        for b in room.bytes() {
+            match b {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(b as char),
+                _ => name.push_str(&format!("%{:02X}", b)),
+            }
+        }
+        name.push_str(".log");
+        self.dir.join(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A history dir of the test's own, emptied first
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("history-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn message(text: &str, seq: u64) -> Event {
+        Event::Message {
+            room: String::from("Main"),
+            from: None,
+            text: text.to_owned(),
+            stamp: Stamp { id: seq, seq, time: 0 },
+        }
+    }
+
+    fn texts(events: Vec<Event>) -> Vec<String> {
+        events
+            .into_iter()
+            .map(|event| match event {
+                Event::Message { text, .. } => text,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replays_the_last_events_also_after_a_restart() {
+        let dir = dir("replay");
+        let mut history = History::new(&dir, 2);
+        for (seq, text) in ["one", "two", "three"].iter().enumerate() {
+            history.append("Main", &message(text, seq as u64 + 1));
+        }
+        assert_eq!(texts(history.replay("Main")), vec!["two", "three"]);
+        assert!(history.replay("elsewhere").is_empty());
+
+        let mut history = History::new(&dir, 2);
+        assert_eq!(texts(history.replay("Main")), vec!["two", "three"]);
+        history.append("Main", &message("four", 4));
+        assert_eq!(texts(history.replay("Main")), vec!["three", "four"]);
+    }
+
+    #[test]
+    fn loads_logs_of_bare_text_lines() {
+        let dir = dir("text");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Main.log"), "hello\nworld\n").unwrap();
+        let mut history = History::new(&dir, 20);
+        assert_eq!(texts(history.replay("Main")), vec!["hello", "world"]);
+    }
+
+    #[test]
+    fn room_names_stay_inside_the_history_dir() {
+        let dir = dir("escape");
+        let mut history = History::new(&dir, 20);
+        history.append("../Main", &message("hi", 1));
+        assert!(dir.join("%2E%2E%2FMain.log").exists());
+        assert_eq!(texts(history.replay("../Main")), vec!["hi"]);
+    }
+
+    #[test]
+    fn sequence_numbers_go_on_without_a_replay_tail() {
+        let dir = dir("no-replay");
+        let mut history = History::new(&dir, 0);
+        for seq in 1..=3 {
+            assert_eq!(history.last_seq("Main"), seq - 1);
+            history.append("Main", &message("hi", seq));
+        }
+        assert!(history.replay("Main").is_empty());
+
+        // Nor after a restart
+        let mut history = History::new(&dir, 0);
+        assert_eq!(history.last_seq("Main"), 3);
+    }
+}
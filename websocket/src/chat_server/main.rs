@@ -1,18 +1,53 @@
 use actix::prelude::*;
 use actix_web::{error, web, App, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
-use std::time::{Duration, Instant};
+use std::net::SocketAddr;
+use std::time::Instant;
+mod admin;
+mod auth;
+mod bans;
+mod cluster;
+mod history;
+mod metrics;
+mod outbox;
+mod poll;
+mod protocol;
+mod ratelimit;
+mod room;
 mod server;
+mod session;
+mod sse;
+mod upload;
+#[path = "../reassembly.rs"]
+mod reassembly;
+#[path = "../resp.rs"]
+mod resp;
+#[path = "../shutdown.rs"]
+mod shutdown;
 
+use config::{default_workers, Config, Heartbeat, Size};
+use protocol::{Event, Format};
+use reassembly::{too_big, Reassembler, MAX_MESSAGE_SIZE};
+use session::{ChatSession, Transport};
 
-const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Optional list of pre-declared rooms, one per line
+const ROOMS_FILE: &str = "./rooms.txt";
+/// Optional list of users that operate every room, one per line. The rights
+/// only go to sessions logged in under the name, see `auth::USERS_FILE`.
+const ADMINS_FILE: &str = "./admins.txt";
+
+/// What every WebSocket session starts with
+#[derive(Clone, Copy)]
+struct WsSettings {
+    heartbeat: Heartbeat,
+    max_message_size: usize,
+}
 
 struct WsChatSession {
-    id: usize,
+    session: ChatSession,
     hb: Instant,
-    room: String,
-    name: Option<String>,
-    addr: Addr<server::ChatServer>,
+    heartbeat: Heartbeat,
+    fragments: Reassembler,
 }
 
 impl Actor for WsChatSession {
@@ -21,25 +56,12 @@ impl Actor for WsChatSession {
         self.hb(ctx);
 
         // Register this session to the chat server
-        let my_addr = ctx.address();
-        self.addr
-            .send(server::Connect {
-                addr: my_addr.recipient(),
-            })
-            .into_actor(self) // Converts the future into ActorFuture
-            .then(|res, act, ctx| {
-                match res {
-                    Ok(id) => act.id = id,
-                    _ => ctx.stop(),
-                }
-                fut::ready(())
-            })
-            .wait(ctx);
+        self.connect(ctx);
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
-        println!("WS chat session [{}] is stopping", self.id);
-        self.addr.do_send(server::Disconnect { id: self.id });
+        println!("WS chat session [{}] is stopping", self.session.id);
+        self.disconnect();
         Running::Stop
     }
 
@@ -49,7 +71,7 @@ impl Handler<server::Message> for WsChatSession {
     type Result = ();
     fn handle(&mut self, msg: server::Message, ctx: &mut Self::Context) -> Self::Result {
         //Handle the message from chat server
-        ctx.text(msg.0);
+        self.handle_event(msg.0, ctx);
     }
 }
 
@@ -57,8 +79,13 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         //Handle the messages coming from WS client
         let msg = match msg {
+            Err(ws::ProtocolError::Overflow) => {
+                self.session.leaving = true;
+                ctx.close(Some(too_big()));
+                ctx.stop();
+                return;
+            }
             Err(_) => {
-                self.addr.do_send(server::Disconnect { id: self.id });
                 ctx.stop();
                 return;
             },
@@ -74,126 +101,249 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
             ws::Message::Pong(_) => {
                 self.hb = Instant::now();
             }
-            ws::Message::Text(text) => {
-                let m = text.trim();
-                // we check for /sss type of messages
-                if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    match v[0] {
-                        "/list" => {
-                            // Send ListRooms message to chat server and wait for
-                            // response
-                            println!("List rooms");
-                            self.addr
-                                .send(server::ListRooms)
-                                .into_actor(self)
-                                .then(|res, _, ctx| {
-                                    match res {
-                                        Ok(rooms) => {
-                                            for room in rooms {
-                                                ctx.text(room);
-                                            }
-                                        }
-                                        _ => println!("Something is wrong"),
-                                    }
-                                    fut::ready(())
-                                })
-                                .wait(ctx)
-                            // .wait(ctx) pauses all events in context,
-                            // so actor wont receive any new messages until it get list
-                            // of rooms back
-                        }
-                        "/join" => {
-                            if v.len() == 2 {
-                                self.room = v[1].to_owned();
-                                self.addr.do_send(server::Join {
-                                    id: self.id,
-                                    room: self.room.clone(),
-                                });
-
-                                ctx.text("joined");
-                            } else {
-                                ctx.text("!!! room name is required");
-                            }
-                        }
-                        "/name" => {
-                            if v.len() == 2 {
-                                self.name = Some(v[1].to_owned());
-                            } else {
-                                ctx.text("!!! name is required");
-                            }
-                        }
-                        _ => ctx.text(format!("!!! unknown command: {:?}", m)),
-                    }
-                } else {
-                    let msg = if let Some(ref name) = self.name {
-                        format!("{}: {}", name, m)
-                    } else {
-                        m.to_owned()
-                    };
-                    // send message to chat server
-                    self.addr.do_send(server::ClientMessage {
-                        id: self.id,
-                        msg,
-                        room: self.room.clone(),
-                    })
-                }
-            }
+            ws::Message::Text(text) => self.handle_input(&text, ctx),
             ws::Message::Binary(_) => println!("Unexpected binary"),
             ws::Message::Close(_) => {
+                self.session.leaving = true;
                 ctx.stop();
             }
-            ws::Message::Continuation(_) => {
-                ctx.stop();
-            }
+            ws::Message::Continuation(item) => match self.fragments.push(item) {
+                Ok(Some(msg)) => StreamHandler::handle(self, Ok(msg), ctx),
+                Ok(None) => (),
+                Err(reason) => {
+                    self.session.leaving = true;
+                    ctx.close(Some(reason));
+                    ctx.stop();
+                }
+            },
             ws::Message::Nop => (),
         }
     }
 }
 
+impl Transport for WsChatSession {
+    fn session(&mut self) -> &mut ChatSession {
+        &mut self.session
+    }
+
+    fn send_event(&mut self, event: Event, ctx: &mut ws::WebsocketContext<Self>) {
+        match self.session.format {
+            Format::Text => {
+                for line in event.lines() {
+                    ctx.text(line);
+                }
+            }
+            Format::Json => ctx.text(event.to_json()),
+        }
+    }
+
+    fn close(&mut self, code: ws::CloseCode, reason: String, ctx: &mut ws::WebsocketContext<Self>) {
+        let reason = ws::CloseReason {
+            code,
+            description: Some(reason),
+        };
+        ctx.close(Some(reason));
+        ctx.stop();
+    }
+}
+
 impl WsChatSession {
-    fn new(srv_addr: Addr<server::ChatServer>) -> Self {
+    fn new(session: ChatSession, settings: WsSettings) -> Self {
         Self {
-            id: 0,
+            session,
             hb: Instant::now(),
-            room: String::from("Main"),
-            name: None,
-            addr: srv_addr,
+            heartbeat: settings.heartbeat,
+            fragments: Reassembler::new(settings.max_message_size),
         }
     }
 
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
         //Start sending heartbeat to WS client
-        ctx.run_interval(HEARTBEAT_TIMEOUT, |act, ctx| {
-            if Instant::now().duration_since(act.hb) > Duration::from_secs(10) {
+        ctx.run_interval(self.heartbeat.interval, |act, ctx| {
+            if Instant::now().duration_since(act.hb) > act.heartbeat.timeout {
                 println!("WS session ping timed out, disconnecting...");
+                act.session.addr.do_send(server::HeartbeatTimeout);
 
-                act.addr.do_send(server::Disconnect { id: act.id });
                 ctx.stop();
                 return;
             }
+
+            act.session.check_idle();
             ctx.ping(b"");
         });
     }
 }
 
+/// Prometheus scrape endpoint
+async fn metrics(srv: web::Data<Addr<server::ChatServer>>) -> Result<HttpResponse, error::Error> {
+    let body = srv
+        .send(server::GetMetrics)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
+
 async fn chat_route(
     req: HttpRequest,
+    users: web::Data<auth::Users>,
+    draining: web::Data<shutdown::Draining>,
+    settings: web::Data<WsSettings>,
     srv: web::Data<Addr<server::ChatServer>>,
     stream: web::Payload,
 ) -> Result<HttpResponse, error::Error> {
-    ws::start(WsChatSession::new(srv.get_ref().clone()), &req, stream)
+    draining.check()?;
+    let session = ChatSession::from_request(&req, &users, srv.get_ref().clone())?;
+    let max_size = settings.max_message_size;
+    reassembly::start(WsChatSession::new(session, **settings), max_size, &req, stream)
+}
+
+/// Read a list file, one entry per line, a missing file just means there
+/// are no entries
+fn load_list(path: &str) -> std::io::Result<Vec<String>> {
+    match std::fs::read_to_string(path) {
+        Ok(list) => Ok(list
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_owned)
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
 }
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    let server = server::ChatServer::default().start();
-    HttpServer::new(move || {
+    let mut config = Config::load("ws-chat-server", "CHAT")
+        .alias("session.resume_grace", "CHAT_RESUME_GRACE")
+        .alias("outbox.slow_consumer", "CHAT_SLOW_CONSUMER")
+        .alias("cluster.broker", "CHAT_CLUSTER");
+    let bind = config.get("server.bind", SocketAddr::from(([0, 0, 0, 0], 9999)));
+    let workers = config.get("server.workers", default_workers());
+    config.check("server.workers", workers > 0, "must be at least 1");
+    let drain_timeout = config.get("server.drain_timeout", shutdown::DRAIN_TIMEOUT);
+    let settings = WsSettings {
+        heartbeat: Heartbeat::from_config(&mut config),
+        max_message_size: config.get("server.max_message_size", Size(MAX_MESSAGE_SIZE)).0,
+    };
+    config.check("server.max_message_size", settings.max_message_size > 0, "must be above zero");
+    // How long a dropped session can be resumed for
+    let resume_grace = config.get("session.resume_grace", server::RESUME_GRACE);
+    let rooms_file = config.get("files.rooms", String::from(ROOMS_FILE));
+    let admins_file = config.get("files.admins", String::from(ADMINS_FILE));
+    let users_file = config.get("files.users", String::from(auth::USERS_FILE));
+    let bans_file = config.get("files.bans", String::from(bans::BANS_FILE));
+    // Pages elsewhere that may connect with the token cookie, comma separated
+    let origins: Vec<String> = config
+        .get("auth.allowed_origins", String::new())
+        .split(',')
+        .map(|origin| origin.trim().trim_end_matches('/').to_owned())
+        .filter(|origin| !origin.is_empty())
+        .collect();
+    let history_dir = config.get("history.dir", String::from(history::HISTORY_DIR));
+    let history_replay = config.get("history.replay", history::HISTORY_REPLAY);
+    let uploads = upload::Uploads::from_config(&mut config);
+    let limits = ratelimit::RateLimits::from_config(&mut config);
+    let backpressure = outbox::Backpressure::from_config(&mut config);
+    let cluster = cluster::ClusterConfig::from_config(&mut config);
+    let admin_token = admin::AdminToken::from_config(&mut config);
+    config.finish()?;
+    if config.print {
+        print!("{}", config);
+        return Ok(());
+    }
+
+    let rooms = load_list(&rooms_file)?;
+    let admins = load_list(&admins_file)?;
+    let users = auth::Users::parse(load_list(&users_file)?).allow_origins(origins);
+    if users.is_empty() {
+        if !admins.is_empty() {
+            // Anyone could /name themselves after an admin
+            println!("{} names admins, but without users in {} nobody can log in as one", admins_file, users_file);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "admins need users"));
+        }
+        println!("No users in {}, anyone can connect", users_file);
+    }
+    std::fs::create_dir_all(&uploads.dir)?;
+    let bans = bans::Bans::load(bans_file);
+    let history = history::History::new(history_dir, history_replay);
+    let mut server = server::ChatServer::new(rooms, admins, bans, history)
+        .rate_limits(limits)
+        .resume_grace(resume_grace)
+        .backpressure(backpressure);
+    if let Some(config) = cluster {
+        server = server.cluster(config);
+    }
+    let server = server.start();
+    let uploads = web::Data::new(uploads);
+    let inputs = web::Data::new(session::Inputs::default());
+    let polls = web::Data::new(poll::Polls::default());
+    let draining = web::Data::new(shutdown::Draining::default());
+    let chat = server.clone();
+    let closing = draining.clone();
+    let http = HttpServer::new(move || {
         App::new()
-            .data(server.clone())
+            .data(chat.clone())
+            .data(users.clone())
+            .data(settings)
+            .app_data(uploads.clone())
+            .app_data(inputs.clone())
+            .app_data(polls.clone())
+            .app_data(closing.clone())
             .service(web::resource("/ws/").route(web::get().to(chat_route)))
+            .service(web::resource("/sse/").route(web::get().to(sse::events)))
+            .service(web::resource("/sse/{token}").route(web::post().to(sse::input)))
+            .service(web::resource("/poll/").route(web::post().to(poll::open)))
+            .service(
+                web::resource("/poll/{token}")
+                    .route(web::get().to(poll::events))
+                    .route(web::post().to(poll::input)),
+            )
+            .service(web::resource("/metrics").route(web::get().to(metrics)))
+            .service(
+                web::scope("/admin")
+                    .data(admin_token.clone())
+                    .route("/rooms", web::get().to(admin::rooms))
+                    .route("/rooms/{room}", web::delete().to(admin::close_room))
+                    .route("/rooms/{room}/members", web::get().to(admin::members))
+                    .route("/rooms/{room}/broadcast", web::post().to(admin::broadcast))
+                    .route("/sessions/{id}", web::delete().to(admin::disconnect)),
+            )
+            .service(web::resource("/upload/{ticket}").route(web::post().to(upload::upload)))
+            .service(web::resource("/files/{room}/{file}").route(web::get().to(upload::download)))
     })
-    .bind("0.0.0.0:9999")?
-    .run()
-    .await
+    .workers(workers)
+    .bind(bind)?
+    .disable_signals()
+    .run();
+
+    let graceful = http.clone();
+    actix_rt::spawn(async move {
+        match shutdown::signalled().await {
+            Ok(signal) => println!("{} received, shutting down", signal),
+            Err(e) => return println!("Can not listen for signals: {}", e),
+        }
+        // No new sessions, then let the ones there are go
+        draining.start();
+        let message = String::from("server is shutting down");
+        let _ = server.send(server::Shutdown { message }).await;
+        let drained = shutdown::drain(drain_timeout, || {
+            let server = server.clone();
+            async move {
+                match server.send(server::GetStats { id: None }).await {
+                    Ok(Ok(stats)) => stats.sessions == 0,
+                    _ => true,
+                }
+            }
+        })
+        .await;
+        if !drained {
+            println!("Sessions still open after {:?}, stopping anyway", drain_timeout);
+        }
+        let _ = server.send(server::Flush).await;
+        graceful.stop(true).await;
+    });
+    http.await
 }
@@ -0,0 +1,143 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Upper bounds of the fan-out latency buckets, in seconds
+const FANOUT_BUCKETS: [f64; 9] = [0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1];
+
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(bucket) = self.bounds.iter().position(|bound| secs <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count);
+    }
+}
+
+/// Counters kept by the chat server, gauges are read off its state when
+/// scraped
+pub struct Metrics {
+    /// Room messages posted
+    pub messages_sent: u64,
+    pub heartbeat_timeouts: u64,
+    /// Time to hand an event to every member of a room
+    pub fanout: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics {
+            messages_sent: 0,
+            heartbeat_timeouts: 0,
+            fanout: Histogram::new(&FANOUT_BUCKETS),
+        }
+    }
+}
+
+/// What the server looks like right now
+pub struct Snapshot {
+    pub sessions: usize,
+    pub detached: usize,
+    pub rooms: usize,
+    /// Public permanent rooms with their member counts. Rooms come and go
+    /// with clients and private ones should not be seen, so the rest only
+    /// count together.
+    pub listed: Vec<(String, usize)>,
+    pub unlisted_members: usize,
+    pub queued: usize,
+    pub dropped: u64,
+    pub slow_disconnects: u64,
+}
+
+impl Metrics {
+    /// Everything in the Prometheus text format
+    pub fn render(&self, snapshot: &Snapshot) -> String {
+        let mut out = String::new();
+        gauge(&mut out, "chat_sessions", "Sessions, including detached ones", snapshot.sessions);
+        gauge(&mut out, "chat_sessions_detached", "Dropped sessions waiting for a resume", snapshot.detached);
+        gauge(&mut out, "chat_rooms", "Rooms", snapshot.rooms);
+
+        let _ = writeln!(out, "# HELP chat_room_members Members of a public permanent room");
+        let _ = writeln!(out, "# TYPE chat_room_members gauge");
+        for (room, members) in &snapshot.listed {
+            let _ = writeln!(out, "chat_room_members{{room=\"{}\"}} {}", escape(room), members);
+        }
+        gauge(
+            &mut out,
+            "chat_unlisted_room_members",
+            "Members of the other rooms, added up",
+            snapshot.unlisted_members,
+        );
+
+        gauge(&mut out, "chat_outbox_queued", "Events waiting for a slow session", snapshot.queued);
+        counter(&mut out, "chat_messages_sent_total", "Room messages posted", self.messages_sent);
+        counter(
+            &mut out,
+            "chat_events_dropped_total",
+            "Events dropped because a session did not keep up",
+            snapshot.dropped,
+        );
+        counter(
+            &mut out,
+            "chat_slow_disconnects_total",
+            "Sessions disconnected for not keeping up",
+            snapshot.slow_disconnects,
+        );
+        counter(
+            &mut out,
+            "chat_heartbeat_timeouts_total",
+            "Sessions that stopped answering pings",
+            self.heartbeat_timeouts,
+        );
+
+        let _ = writeln!(out, "# HELP chat_fanout_seconds Time to hand an event to a room");
+        let _ = writeln!(out, "# TYPE chat_fanout_seconds histogram");
+        self.fanout.render(&mut out, "chat_fanout_seconds");
+        out
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+}
+
+/// Label values are quoted, so quotes, backslashes and newlines need escaping
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,155 @@
+use std::collections::VecDeque;
+
+use config::{Config, Setting};
+use crate::protocol::Event;
+
+/// What to do when a session does not keep up with what it is sent
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SlowConsumer {
+    /// Make room by dropping the oldest queued event
+    DropOldest,
+    /// Drop the event that does not fit
+    DropNewest,
+    /// Disconnect the session
+    Disconnect,
+}
+
+impl Setting for SlowConsumer {
+    fn parse(policy: &str) -> Result<SlowConsumer, String> {
+        match policy {
+            "drop-oldest" => Ok(SlowConsumer::DropOldest),
+            "drop-newest" => Ok(SlowConsumer::DropNewest),
+            "disconnect" => Ok(SlowConsumer::Disconnect),
+            _ => Err(format!("expected drop-oldest, drop-newest or disconnect, got {:?}", policy)),
+        }
+    }
+
+    fn show(&self) -> String {
+        let policy = match self {
+            SlowConsumer::DropOldest => "drop-oldest",
+            SlowConsumer::DropNewest => "drop-newest",
+            SlowConsumer::Disconnect => "disconnect",
+        };
+        format!("{:?}", policy)
+    }
+}
+
+/// Outbound queue settings, applied to every session
+#[derive(Clone, Debug)]
+pub struct Backpressure {
+    /// Events queued for a session whose mailbox is full
+    pub capacity: usize,
+    pub policy: SlowConsumer,
+}
+
+impl Default for Backpressure {
+    fn default() -> Backpressure {
+        Backpressure {
+            capacity: 256,
+            policy: SlowConsumer::DropOldest,
+        }
+    }
+}
+
+impl Backpressure {
+    /// The `[outbox]` settings, the policy is `drop-oldest`, `drop-newest`
+    /// or `disconnect`
+    pub fn from_config(config: &mut Config) -> Backpressure {
+        let defaults = Backpressure::default();
+        let backpressure = Backpressure {
+            capacity: config.get("outbox.capacity", defaults.capacity),
+            policy: config.get("outbox.slow_consumer", defaults.policy),
+        };
+        config.check("outbox.capacity", backpressure.capacity > 0, "must be above zero");
+        backpressure
+    }
+}
+
+/// Events waiting for room in a session's mailbox
+#[derive(Default)]
+pub struct Outbox {
+    queue: VecDeque<Event>,
+    /// Events dropped so far
+    pub dropped: u64,
+    /// Set when the `Disconnect` policy kicked in
+    pub overflowed: bool,
+}
+
+impl Outbox {
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Queue an event, applying `policy` once `capacity` events are waiting
+    pub fn push(&mut self, event: Event, capacity: usize, policy: SlowConsumer) {
+        if self.queue.len() >= capacity {
+            match policy {
+                SlowConsumer::DropOldest => {
+                    self.queue.pop_front();
+                    self.dropped += 1;
+                }
+                SlowConsumer::DropNewest => {
+                    self.dropped += 1;
+                    return;
+                }
+                SlowConsumer::Disconnect => {
+                    self.overflowed = true;
+                    return;
+                }
+            }
+        }
+        self.queue.push_back(event);
+    }
+
+    pub fn pop(&mut self) -> Option<Event> {
+        self.queue.pop_front()
+    }
+
+    /// Put back an event that could not be delivered after all
+    pub fn unpop(&mut self, event: Event) {
+        self.queue.push_front(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(n: usize) -> Event {
+        Event::Error {
+            message: n.to_string(),
+        }
+    }
+
+    fn drain(outbox: &mut Outbox) -> Vec<String> {
+        std::iter::from_fn(|| outbox.pop())
+            .map(|event| match event {
+                Event::Error { message } => message,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn slow_consumer_policies() {
+        let mut oldest = Outbox::default();
+        let mut newest = Outbox::default();
+        let mut disconnect = Outbox::default();
+        for n in 0..4 {
+            oldest.push(event(n), 2, SlowConsumer::DropOldest);
+            newest.push(event(n), 2, SlowConsumer::DropNewest);
+            disconnect.push(event(n), 2, SlowConsumer::Disconnect);
+        }
+
+        assert_eq!(drain(&mut oldest), vec!["2", "3"]);
+        assert_eq!(oldest.dropped, 2);
+        assert_eq!(drain(&mut newest), vec!["0", "1"]);
+        assert_eq!(newest.dropped, 2);
+        assert!(disconnect.overflowed);
+        assert_eq!(drain(&mut disconnect), vec!["0", "1"]);
+    }
+}
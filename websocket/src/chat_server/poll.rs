@@ -0,0 +1,242 @@
+use actix::prelude::*;
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws::CloseCode;
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::auth::Users;
+use crate::protocol::{Event, Format};
+use crate::server;
+use crate::shutdown::Draining;
+use crate::session::{ChatSession, Input, Registry, Transport};
+
+/// How long a poll waits for something to happen before coming back empty
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+/// A session nobody polled for this long is given up on
+const POLL_EXPIRY: Duration = Duration::from_secs(60);
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Events kept for the next poll, older ones are dropped first
+const MAX_BUFFERED: usize = 500;
+
+pub type Polls = Registry<Addr<PollSession>>;
+
+/// What a poll gets: the events after its cursor and the cursor to send
+/// next time
+#[derive(Serialize)]
+pub struct Batch {
+    session: String,
+    cursor: u64,
+    events: Vec<Value>,
+}
+
+/// Wait for events after `since`, the ones up to it are acknowledged
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Poll {
+    since: u64,
+    wait: bool,
+    tx: oneshot::Sender<Batch>,
+}
+
+/// A chat session for clients that poll for their events over plain HTTP
+/// and post their input
+pub struct PollSession {
+    session: ChatSession,
+    polls: web::Data<Polls>,
+    token: String,
+    /// Events not acknowledged yet, by cursor
+    buffer: VecDeque<(u64, Event)>,
+    cursor: u64,
+    /// The poll waiting for events, with its timeout
+    waiting: Option<(oneshot::Sender<Batch>, SpawnHandle)>,
+    /// When the last poll came in or finished
+    last_poll: Instant,
+    /// The server is done with the session, it goes once the client knows
+    closed: bool,
+}
+
+impl Actor for PollSession {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.connect(ctx);
+        ctx.run_interval(EXPIRY_CHECK_INTERVAL, |act, ctx| {
+            if act.waiting.is_none() && Instant::now().duration_since(act.last_poll) > POLL_EXPIRY {
+                println!("Poll session [{}] expired", act.session.id);
+                return ctx.stop();
+            }
+            act.session.check_idle();
+        });
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        println!("Poll chat session [{}] is stopping", self.session.id);
+        self.polls.remove(&self.token, self.session.conn);
+        self.disconnect();
+        Running::Stop
+    }
+}
+
+impl Handler<server::Message> for PollSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: server::Message, ctx: &mut Self::Context) -> Self::Result {
+        self.handle_event(msg.0, ctx);
+    }
+}
+
+impl Handler<Input> for PollSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Input, ctx: &mut Self::Context) -> Self::Result {
+        self.handle_input(&msg.0, ctx);
+    }
+}
+
+impl Handler<Poll> for PollSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Poll, ctx: &mut Self::Context) -> Self::Result {
+        self.last_poll = Instant::now();
+        while self.buffer.front().is_some_and(|(cursor, _)| *cursor <= msg.since) {
+            self.buffer.pop_front();
+        }
+        // Only one poll waits at a time, an older one comes back empty
+        self.answer(ctx);
+        if !self.buffer.is_empty() || !msg.wait || self.closed {
+            let _ = msg.tx.send(self.batch());
+            if self.closed {
+                ctx.stop();
+            }
+            return;
+        }
+        let timeout = ctx.run_later(POLL_TIMEOUT, |act, ctx| act.answer(ctx));
+        self.waiting = Some((msg.tx, timeout));
+    }
+}
+
+impl Transport for PollSession {
+    fn session(&mut self) -> &mut ChatSession {
+        &mut self.session
+    }
+
+    fn send_event(&mut self, event: Event, ctx: &mut Context<Self>) {
+        self.cursor += 1;
+        self.buffer.push_back((self.cursor, event));
+        if self.buffer.len() > MAX_BUFFERED {
+            self.buffer.pop_front();
+        }
+        self.answer(ctx);
+    }
+
+    fn close(&mut self, _: CloseCode, _: String, ctx: &mut Context<Self>) {
+        self.closed = true;
+        if self.waiting.is_some() {
+            self.answer(ctx);
+            ctx.stop();
+        }
+    }
+
+    fn attached(&mut self, token: &str, ctx: &mut Context<Self>) {
+        self.token = token.to_owned();
+        self.polls.insert(token.to_owned(), self.session.conn, ctx.address());
+    }
+}
+
+impl PollSession {
+    /// Everything not acknowledged yet
+    fn batch(&self) -> Batch {
+        let events = self
+            .buffer
+            .iter()
+            .flat_map(|(_, event)| match self.session.format {
+                Format::Text => event.lines().into_iter().map(Value::String).collect(),
+                Format::Json => vec![serde_json::to_value(event).expect("events always serialize")],
+            })
+            .collect();
+        Batch {
+            session: self.token.clone(),
+            cursor: self.cursor,
+            events,
+        }
+    }
+
+    /// Send the waiting poll what there is
+    fn answer(&mut self, ctx: &mut Context<Self>) {
+        if let Some((tx, timeout)) = self.waiting.take() {
+            ctx.cancel_future(timeout);
+            let _ = tx.send(self.batch());
+            self.last_poll = Instant::now();
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PollQuery {
+    #[serde(default)]
+    since: u64,
+}
+
+async fn poll(addr: &Addr<PollSession>, since: u64, wait: bool) -> Result<Batch, Error> {
+    let (tx, rx) = oneshot::channel();
+    addr.send(Poll { since, wait, tx })
+        .await
+        .map_err(|_| error::ErrorGone("session is closed"))?;
+    rx.await.map_err(|_| error::ErrorGone("session is closed"))
+}
+
+/// Start a new or resumed session, answering with its token and first
+/// events
+pub async fn open(
+    req: HttpRequest,
+    users: web::Data<Users>,
+    draining: web::Data<Draining>,
+    polls: web::Data<Polls>,
+    srv: web::Data<Addr<server::ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    draining.check()?;
+    let session = ChatSession::from_request(&req, &users, srv.get_ref().clone())?;
+    let addr = PollSession {
+        session,
+        polls: polls.clone(),
+        token: String::new(),
+        buffer: VecDeque::new(),
+        cursor: 0,
+        waiting: None,
+        last_poll: Instant::now(),
+        closed: false,
+    }
+    .start();
+    // Only comes back once the server gave out the session
+    let batch = poll(&addr, 0, false).await?;
+    Ok(HttpResponse::Ok().json(batch))
+}
+
+/// Wait for the events after the `since` cursor
+pub async fn events(
+    token: web::Path<String>,
+    query: web::Query<PollQuery>,
+    polls: web::Data<Polls>,
+) -> Result<HttpResponse, Error> {
+    let addr = polls
+        .get(&token)
+        .ok_or_else(|| error::ErrorNotFound("no such session"))?;
+    let batch = poll(&addr, query.since, true).await?;
+    Ok(HttpResponse::Ok().json(batch))
+}
+
+/// A line of input, what a WebSocket client would send as a text message
+pub async fn input(
+    token: web::Path<String>,
+    body: String,
+    polls: web::Data<Polls>,
+) -> Result<HttpResponse, Error> {
+    let addr = polls
+        .get(&token)
+        .ok_or_else(|| error::ErrorNotFound("no such session"))?;
+    addr.do_send(Input(body));
+    Ok(HttpResponse::Accepted().finish())
+}
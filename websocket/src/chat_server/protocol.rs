@@ -0,0 +1,515 @@
+use serde::{Deserialize, Serialize};
+
+/// Wire format of a chat connection, picked with `?format=json` on `/ws/`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    pub fn from_query(query: &str) -> Format {
+        let json = query
+            .split('&')
+            .any(|pair| pair == "format=json");
+        if json {
+            Format::Json
+        } else {
+            Format::Text
+        }
+    }
+}
+
+/// Value of a query string parameter, taken as is
+pub fn query_param<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query.split('&').find_map(|pair| {
+        let mut kv = pair.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(k), Some(value)) if k == key && !value.is_empty() => Some(value),
+            _ => None,
+        }
+    })
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Presence {
+    Online,
+    Away,
+    Idle,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Member {
+    pub name: String,
+    pub presence: Presence,
+    /// Set by `/away <message>`
+    pub message: Option<String>,
+}
+
+/// Where a room message sits in the room's history. Logs written before
+/// messages were stamped read back as all zeros.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct Stamp {
+    /// Unique across rooms
+    pub id: u64,
+    /// Goes up by one with every message in the room, so clients can spot
+    /// what they missed. In a cluster every node numbers the messages it
+    /// hands out itself, ids as well, so they only mean something to the
+    /// node a client is connected to.
+    pub seq: u64,
+    /// Milliseconds since the Unix epoch
+    pub time: u64,
+}
+
+/// Commands sent by clients, either as JSON or as `/command` lines
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    /// Post to `room`, or to the active room when it is not given
+    Message { text: String, room: Option<String> },
+    Join { room: String, password: Option<String> },
+    Leave { room: Option<String> },
+    Switch { room: String },
+    Name { name: String },
+    Msg { to: String, text: String },
+    Who { room: Option<String> },
+    Invite { name: String, room: Option<String> },
+    Kick { name: String, room: Option<String> },
+    /// Ban a name or an IP address from the room
+    Ban { name: String, room: Option<String> },
+    Unban { name: String, room: Option<String> },
+    Mute { name: String, seconds: u64, room: Option<String> },
+    Op { name: String, room: Option<String> },
+    /// Ask where to upload files to share them in `room`, or the active room
+    Upload { room: Option<String> },
+    /// Start or stop typing in `room`, or in the active room. Clients repeat
+    /// `typing: true` while the user keeps typing.
+    Typing { room: Option<String>, typing: bool },
+    Away { message: Option<String> },
+    Back,
+    /// Acknowledge every message of `room`, or of the active room, up to
+    /// `seq` as delivered, or as read when `read` is set
+    Ack {
+        room: Option<String>,
+        seq: u64,
+        #[serde(default)]
+        read: bool,
+    },
+    /// Send again the messages after `since` that are still kept around
+    History { room: Option<String>, since: u64 },
+    /// Change the access rules of `room`, or of the active room.
+    /// An empty password removes it.
+    Configure {
+        room: Option<String>,
+        private: Option<bool>,
+        password: Option<String>,
+    },
+    List,
+    /// Server counters, for server admins
+    Stats,
+}
+
+impl Command {
+    /// Parse a line typed by a human: `/command args` or a plain message
+    pub fn parse(text: &str) -> Result<Command, String> {
+        let m = text.trim();
+        if !m.starts_with('/') {
+            return Ok(Command::Message {
+                text: m.to_owned(),
+                room: None,
+            });
+        }
+
+        let v: Vec<&str> = m.splitn(2, ' ').collect();
+        let arg = v.get(1).map(|arg| arg.trim()).filter(|arg| !arg.is_empty());
+        match v[0] {
+            "/list" => Ok(Command::List),
+            "/stats" => Ok(Command::Stats),
+            "/join" => {
+                let args: Vec<&str> = arg.map_or(Vec::new(), |arg| arg.splitn(2, ' ').collect());
+                match args.as_slice() {
+                    [room] => Ok(Command::Join {
+                        room: (*room).to_owned(),
+                        password: None,
+                    }),
+                    [room, password] => Ok(Command::Join {
+                        room: (*room).to_owned(),
+                        password: Some(password.trim().to_owned()),
+                    }),
+                    _ => Err(String::from("room name is required")),
+                }
+            }
+            "/leave" => Ok(Command::Leave { room: arg.map(str::to_owned) }),
+            "/switch" => arg
+                .map(|room| Command::Switch { room: room.to_owned() })
+                .ok_or_else(|| String::from("room name is required")),
+            "/say" => {
+                let args: Vec<&str> = arg.map_or(Vec::new(), |arg| arg.splitn(2, ' ').collect());
+                if args.len() == 2 {
+                    Ok(Command::Message {
+                        text: args[1].trim().to_owned(),
+                        room: Some(args[0].to_owned()),
+                    })
+                } else {
+                    Err(String::from("usage: /say <room> <text>"))
+                }
+            }
+            "/name" => arg
+                .map(|name| Command::Name { name: name.to_owned() })
+                .ok_or_else(|| String::from("name is required")),
+            "/who" => Ok(Command::Who { room: arg.map(str::to_owned) }),
+            "/invite" => {
+                let args: Vec<&str> = arg.map_or(Vec::new(), |arg| arg.split_whitespace().collect());
+                match args.as_slice() {
+                    [name] => Ok(Command::Invite {
+                        name: (*name).to_owned(),
+                        room: None,
+                    }),
+                    [name, room] => Ok(Command::Invite {
+                        name: (*name).to_owned(),
+                        room: Some((*room).to_owned()),
+                    }),
+                    _ => Err(String::from("usage: /invite <name> [room]")),
+                }
+            }
+            "/kick" | "/ban" | "/unban" | "/op" => {
+                let args: Vec<&str> = arg.map_or(Vec::new(), |arg| arg.split_whitespace().collect());
+                let (name, room) = match args.as_slice() {
+                    [name] => ((*name).to_owned(), None),
+                    [name, room] => ((*name).to_owned(), Some((*room).to_owned())),
+                    _ => return Err(format!("usage: {} <name> [room]", v[0])),
+                };
+                Ok(match v[0] {
+                    "/kick" => Command::Kick { name, room },
+                    "/ban" => Command::Ban { name, room },
+                    "/unban" => Command::Unban { name, room },
+                    _ => Command::Op { name, room },
+                })
+            }
+            "/mute" => {
+                let args: Vec<&str> = arg.map_or(Vec::new(), |arg| arg.split_whitespace().collect());
+                let (name, seconds, room) = match args.as_slice() {
+                    [name, seconds] => (name, seconds, None),
+                    [name, seconds, room] => (name, seconds, Some((*room).to_owned())),
+                    _ => return Err(String::from("usage: /mute <name> <seconds> [room]")),
+                };
+                let seconds = seconds
+                    .parse()
+                    .map_err(|_| String::from("usage: /mute <name> <seconds> [room]"))?;
+                Ok(Command::Mute {
+                    name: (*name).to_owned(),
+                    seconds,
+                    room,
+                })
+            }
+            "/upload" => Ok(Command::Upload { room: arg.map(str::to_owned) }),
+            "/typing" => Ok(Command::Typing {
+                room: arg.map(str::to_owned),
+                typing: true,
+            }),
+            "/away" => Ok(Command::Away { message: arg.map(str::to_owned) }),
+            "/back" => Ok(Command::Back),
+            "/ack" | "/read" => {
+                let args: Vec<&str> = arg.map_or(Vec::new(), |arg| arg.split_whitespace().collect());
+                let (seq, room) = match args.as_slice() {
+                    [seq] => (seq, None),
+                    [seq, room] => (seq, Some((*room).to_owned())),
+                    _ => return Err(format!("usage: {} <seq> [room]", v[0])),
+                };
+                let seq = seq
+                    .parse()
+                    .map_err(|_| format!("usage: {} <seq> [room]", v[0]))?;
+                Ok(Command::Ack {
+                    room,
+                    seq,
+                    read: v[0] == "/read",
+                })
+            }
+            "/history" => {
+                let args: Vec<&str> = arg.map_or(Vec::new(), |arg| arg.split_whitespace().collect());
+                let (since, room) = match args.as_slice() {
+                    [] => (&"0", None),
+                    [since] => (since, None),
+                    [since, room] => (since, Some((*room).to_owned())),
+                    _ => return Err(String::from("usage: /history [since] [room]")),
+                };
+                let since = since
+                    .parse()
+                    .map_err(|_| String::from("usage: /history [since] [room]"))?;
+                Ok(Command::History { room, since })
+            }
+            "/private" | "/public" => Ok(Command::Configure {
+                room: arg.map(str::to_owned),
+                private: Some(v[0] == "/private"),
+                password: None,
+            }),
+            "/password" => Ok(Command::Configure {
+                room: None,
+                private: None,
+                password: Some(arg.unwrap_or("").to_owned()),
+            }),
+            "/msg" => {
+                let args: Vec<&str> = arg.map_or(Vec::new(), |arg| arg.splitn(2, ' ').collect());
+                if args.len() == 2 {
+                    Ok(Command::Msg {
+                        to: args[0].to_owned(),
+                        text: args[1].trim().to_owned(),
+                    })
+                } else {
+                    Err(String::from("usage: /msg <name> <text>"))
+                }
+            }
+            _ => Err(format!("unknown command: {:?}", m)),
+        }
+    }
+}
+
+/// Everything the server sends to clients
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Message {
+        room: String,
+        from: Option<String>,
+        text: String,
+        #[serde(flatten)]
+        stamp: Stamp,
+    },
+    /// Tells the sender how its message was stamped
+    Sent {
+        room: String,
+        #[serde(flatten)]
+        stamp: Stamp,
+    },
+    /// Someone got or read a message of ours
+    Receipt {
+        room: String,
+        id: u64,
+        seq: u64,
+        name: String,
+        read: bool,
+    },
+    Private { from: String, text: String },
+    Join { room: String, name: String },
+    Leave { room: String, name: String },
+    Disconnect { room: String, name: String },
+    Rename { old: String, new: String },
+    Joined { room: String },
+    Left { room: String },
+    Active { room: String },
+    Rooms { rooms: Vec<String> },
+    Stats {
+        sessions: usize,
+        rooms: usize,
+        queued: usize,
+        dropped: u64,
+        slow_disconnects: u64,
+    },
+    /// Reconnect with `?resume=<token>` to pick the session up again
+    Session { token: String, resumed: bool },
+    Members { room: String, members: Vec<Member> },
+    Typing { room: String, name: String, typing: bool },
+    Presence {
+        name: String,
+        presence: Presence,
+        message: Option<String>,
+    },
+    Invite { room: String, from: String },
+    /// Where to POST files to share them in `room`
+    Upload { room: String, url: String },
+    /// GET `url` with the session token in `X-Chat-Session` to fetch the file
+    File {
+        room: String,
+        from: String,
+        name: String,
+        size: usize,
+        url: String,
+        #[serde(flatten)]
+        stamp: Stamp,
+    },
+    Kick { room: String, by: String, name: String },
+    Ban { room: String, by: String, name: String },
+    Unban { room: String, by: String, name: String },
+    Mute {
+        room: String,
+        by: String,
+        name: String,
+        seconds: u64,
+    },
+    Op { room: String, by: String, name: String },
+    RoomSettings {
+        room: String,
+        private: bool,
+        password: bool,
+    },
+    Error { message: String },
+    /// The server is closing the connection
+    Disconnected { reason: String },
+    /// The server is going down, every connection is closed
+    Shutdown { message: String },
+}
+
+impl Event {
+    /// Render the event for plain text clients, one frame per line
+    pub fn lines(&self) -> Vec<String> {
+        match self {
+            Event::Message { room, from: Some(from), text, .. } => {
+                vec![format!("[{}] {}: {}", room, from, text)]
+            }
+            Event::Message { room, from: None, text, .. } => vec![format!("[{}] {}", room, text)],
+            // Only of use to clients that track sequence numbers
+            Event::Sent { .. } => Vec::new(),
+            Event::Receipt { room, seq, name, read, .. } => vec![format!(
+                "[{}] {} {} message #{}",
+                room,
+                name,
+                if *read { "read" } else { "got" },
+                seq
+            )],
+            Event::Private { from, text } => vec![format!("[private] {}: {}", from, text)],
+            Event::Join { room, name } => vec![format!("{} joined {}", name, room)],
+            Event::Leave { room, name } => vec![format!("{} left {}", name, room)],
+            Event::Disconnect { name, .. } => vec![format!("{} disconnected", name)],
+            Event::Rename { old, new } => vec![format!("{} is now known as {}", old, new)],
+            Event::Joined { .. } => vec![String::from("joined")],
+            Event::Left { .. } => vec![String::from("left")],
+            Event::Active { room } => vec![format!("talking in {}", room)],
+            Event::Rooms { rooms } => rooms.clone(),
+            Event::Stats { sessions, rooms, queued, dropped, slow_disconnects } => vec![format!(
+                "sessions: {}, rooms: {}, queued events: {}, dropped events: {}, slow disconnects: {}",
+                sessions, rooms, queued, dropped, slow_disconnects
+            )],
+            Event::Session { token, resumed: false } => {
+                vec![format!("reconnect with ?resume={} to pick up where you left off", token)]
+            }
+            Event::Session { resumed: true, .. } => vec![String::from("resumed")],
+            Event::Members { members, .. } => members
+                .iter()
+                .map(|member| match (member.presence, &member.message) {
+                    (Presence::Online, _) => member.name.clone(),
+                    (Presence::Away, Some(message)) => format!("{} (away: {})", member.name, message),
+                    (Presence::Away, None) => format!("{} (away)", member.name),
+                    (Presence::Idle, _) => format!("{} (idle)", member.name),
+                })
+                .collect(),
+            Event::Typing { room, name, typing: true } => vec![format!("[{}] {} is typing...", room, name)],
+            Event::Typing { room, name, typing: false } => {
+                vec![format!("[{}] {} stopped typing", room, name)]
+            }
+            Event::Presence { name, presence, message } => vec![match (presence, message) {
+                (Presence::Online, _) => format!("{} is back", name),
+                (Presence::Away, Some(message)) => format!("{} is away: {}", name, message),
+                (Presence::Away, None) => format!("{} is away", name),
+                (Presence::Idle, _) => format!("{} is idle", name),
+            }],
+            Event::Invite { room, from } => {
+                vec![format!("{} invited you to {}, /join {} to accept", from, room, room)]
+            }
+            Event::Upload { room, url } => {
+                vec![format!("POST files to {} as multipart/form-data to share them in {}", url, room)]
+            }
+            Event::File { room, from, name, size, url, .. } => {
+                vec![format!("[{}] {} shared {} ({} bytes): {}", room, from, name, size, url)]
+            }
+            Event::Kick { room, by, name } => vec![format!("{} kicked {} from {}", by, name, room)],
+            Event::Ban { room, by, name } => vec![format!("{} banned {} from {}", by, name, room)],
+            Event::Unban { room, by, name } => {
+                vec![format!("{} lifted the ban on {} in {}", by, name, room)]
+            }
+            Event::Mute { room, by, name, seconds } => {
+                vec![format!("{} muted {} in {} for {}s", by, name, room, seconds)]
+            }
+            Event::Op { room, by, name } => {
+                vec![format!("{} made {} an operator of {}", by, name, room)]
+            }
+            Event::RoomSettings { room, private, password } => vec![format!(
+                "{} is now {}, {}",
+                room,
+                if *private { "private" } else { "public" },
+                if *password { "with a password" } else { "without a password" },
+            )],
+            Event::Error { message } => vec![format!("!!! {}", message)],
+            Event::Disconnected { reason } => vec![format!("!!! disconnected: {}", reason)],
+            Event::Shutdown { message } => vec![format!("!!! {}", message)],
+        }
+    }
+
+    /// The stamp of room messages that are kept in the history
+    pub fn stamp(&self) -> Option<&Stamp> {
+        match self {
+            Event::Message { stamp, .. } | Event::File { stamp, .. } => Some(stamp),
+            _ => None,
+        }
+    }
+
+    pub fn stamp_mut(&mut self) -> Option<&mut Stamp> {
+        match self {
+            Event::Message { stamp, .. } | Event::File { stamp, .. } => Some(stamp),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("events always serialize")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Command {
+        Command::parse(line).unwrap()
+    }
+
+    #[test]
+    fn plain_lines_are_messages_to_the_active_room() {
+        let message = Command::Message {
+            text: String::from("hello /there"),
+            room: None,
+        };
+        assert_eq!(parse("  hello /there "), message);
+    }
+
+    #[test]
+    fn commands_take_their_arguments() {
+        let join = Command::Join {
+            room: String::from("rust"),
+            password: Some(String::from("open sesame")),
+        };
+        assert_eq!(parse("/join rust open sesame"), join);
+        let say = Command::Message {
+            text: String::from("hi all"),
+            room: Some(String::from("rust")),
+        };
+        assert_eq!(parse("/say rust hi all"), say);
+        let mute = Command::Mute {
+            name: String::from("bob"),
+            seconds: 30,
+            room: Some(String::from("rust")),
+        };
+        assert_eq!(parse("/mute bob 30 rust"), mute);
+        let read = Command::Ack {
+            room: None,
+            seq: 7,
+            read: true,
+        };
+        assert_eq!(parse("/read 7"), read);
+        assert_eq!(parse("/history"), Command::History { room: None, since: 0 });
+        assert_eq!(parse("/leave"), Command::Leave { room: None });
+    }
+
+    #[test]
+    fn bad_commands_are_refused() {
+        assert_eq!(Command::parse("/join").unwrap_err(), "room name is required");
+        assert_eq!(Command::parse("/say rust").unwrap_err(), "usage: /say <room> <text>");
+        assert_eq!(
+            Command::parse("/mute bob soon").unwrap_err(),
+            "usage: /mute <name> <seconds> [room]"
+        );
+        assert_eq!(Command::parse("/ack").unwrap_err(), "usage: /ack <seq> [room]");
+        assert_eq!(Command::parse("/dance").unwrap_err(), "unknown command: \"/dance\"");
+    }
+}
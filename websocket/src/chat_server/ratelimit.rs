@@ -0,0 +1,204 @@
+use std::time::{Duration, Instant};
+
+use config::Config;
+
+/// Flood protection settings, applied to every session
+#[derive(Clone, Debug)]
+pub struct RateLimits {
+    /// Sustained messages per second, bursts of up to `message_burst`
+    pub messages_per_sec: f64,
+    pub message_burst: f64,
+    /// Bytes of message text per minute
+    pub bytes_per_min: f64,
+    /// Strikes before the session is muted for `mute_for`
+    pub strikes_to_mute: u32,
+    pub mute_for: Duration,
+    /// Strikes before the session is disconnected
+    pub strikes_to_disconnect: u32,
+    /// Strikes are forgotten after this long without a new one
+    pub strike_decay: Duration,
+}
+
+impl Default for RateLimits {
+    fn default() -> RateLimits {
+        RateLimits {
+            messages_per_sec: 2.0,
+            message_burst: 5.0,
+            bytes_per_min: 16.0 * 1024.0,
+            strikes_to_mute: 3,
+            mute_for: Duration::from_secs(30),
+            strikes_to_disconnect: 10,
+            strike_decay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RateLimits {
+    /// The `[rate]` settings
+    pub fn from_config(config: &mut Config) -> RateLimits {
+        let defaults = RateLimits::default();
+        let limits = RateLimits {
+            messages_per_sec: config.get("rate.messages_per_sec", defaults.messages_per_sec),
+            message_burst: config.get("rate.burst", defaults.message_burst),
+            bytes_per_min: config.get("rate.bytes_per_min", defaults.bytes_per_min),
+            strikes_to_mute: config.get("rate.strikes_to_mute", defaults.strikes_to_mute),
+            mute_for: config.get("rate.mute_for", defaults.mute_for),
+            strikes_to_disconnect: config.get("rate.strikes_to_disconnect", defaults.strikes_to_disconnect),
+            strike_decay: config.get("rate.strike_decay", defaults.strike_decay),
+        };
+        config.check("rate.messages_per_sec", limits.messages_per_sec > 0.0, "must be above zero");
+        config.check("rate.burst", limits.message_burst >= 1.0, "must be at least 1");
+        config.check("rate.bytes_per_min", limits.bytes_per_min > 0.0, "must be above zero");
+        limits
+    }
+}
+
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    per_sec: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, per_sec: f64) -> TokenBucket {
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            per_sec,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+        self.last = now;
+    }
+
+    fn has(&self, n: f64) -> bool {
+        self.tokens >= n
+    }
+}
+
+/// What to do with a message
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Bigger than the byte budget can ever hold, with that budget
+    TooLong(usize),
+    Warn,
+    Mute(Duration),
+    Muted(Duration),
+    Disconnect,
+}
+
+/// Per session flood state, escalating from warnings to a mute to a
+/// disconnect as strikes pile up
+pub struct Limiter {
+    limits: RateLimits,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    strikes: u32,
+    last_strike: Option<Instant>,
+    muted_until: Option<Instant>,
+}
+
+impl Limiter {
+    pub fn new(limits: &RateLimits) -> Limiter {
+        Limiter {
+            messages: TokenBucket::new(limits.message_burst, limits.messages_per_sec),
+            bytes: TokenBucket::new(limits.bytes_per_min, limits.bytes_per_min / 60.0),
+            limits: limits.clone(),
+            strikes: 0,
+            last_strike: None,
+            muted_until: None,
+        }
+    }
+
+    /// Account for a message of `len` bytes
+    pub fn check(&mut self, len: usize) -> Verdict {
+        // Waiting would not help, so it is no strike either
+        if len as f64 > self.bytes.capacity {
+            return Verdict::TooLong(self.bytes.capacity as usize);
+        }
+        let now = Instant::now();
+        if let Some(last) = self.last_strike {
+            if now.duration_since(last) >= self.limits.strike_decay {
+                self.strikes = 0;
+                self.last_strike = None;
+            }
+        }
+
+        self.messages.refill(now);
+        self.bytes.refill(now);
+        let muted = self
+            .muted_until
+            .and_then(|until| until.checked_duration_since(now));
+        let len = len as f64;
+        if muted.is_none() && self.messages.has(1.0) && self.bytes.has(len) {
+            self.messages.tokens -= 1.0;
+            self.bytes.tokens -= len;
+            return Verdict::Allow;
+        }
+
+        self.strikes += 1;
+        self.last_strike = Some(now);
+        if self.strikes >= self.limits.strikes_to_disconnect {
+            Verdict::Disconnect
+        } else if let Some(left) = muted {
+            Verdict::Muted(left)
+        } else if self.strikes >= self.limits.strikes_to_mute {
+            self.muted_until = Some(now + self.limits.mute_for);
+            Verdict::Mute(self.limits.mute_for)
+        } else {
+            Verdict::Warn
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One message, then nothing for a long while
+    fn limits() -> RateLimits {
+        RateLimits {
+            messages_per_sec: 0.001,
+            message_burst: 1.0,
+            bytes_per_min: 100.0,
+            strikes_to_mute: 2,
+            mute_for: Duration::from_secs(60),
+            strikes_to_disconnect: 4,
+            strike_decay: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn strikes_escalate_from_a_warning_to_a_mute_to_a_disconnect() {
+        let mut limiter = Limiter::new(&limits());
+        assert_eq!(limiter.check(1), Verdict::Allow);
+        assert_eq!(limiter.check(1), Verdict::Warn);
+        assert_eq!(limiter.check(1), Verdict::Mute(Duration::from_secs(60)));
+        assert!(matches!(limiter.check(1), Verdict::Muted(_)));
+        assert_eq!(limiter.check(1), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn strikes_are_forgotten_after_the_decay() {
+        let mut limiter = Limiter::new(&limits());
+        assert_eq!(limiter.check(1), Verdict::Allow);
+        assert_eq!(limiter.check(1), Verdict::Warn);
+        limiter.last_strike = Some(Instant::now() - limits().strike_decay);
+        // A first strike again, not the second that mutes
+        assert_eq!(limiter.check(1), Verdict::Warn);
+    }
+
+    #[test]
+    fn a_message_over_the_byte_budget_is_no_strike() {
+        let mut limiter = Limiter::new(&limits());
+        assert_eq!(limiter.check(101), Verdict::TooLong(100));
+        assert_eq!(limiter.check(100), Verdict::Allow);
+        assert_eq!(limiter.strikes, 0);
+    }
+}
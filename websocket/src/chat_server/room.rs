@@ -0,0 +1,148 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::bans::Ban;
+
+/// How many recent messages a room remembers the senders of, for receipts
+pub const RECEIPT_WINDOW: usize = 100;
+
+/// How far a member has acknowledged the messages of a room
+#[derive(Clone, Copy, Default)]
+pub struct Acks {
+    pub delivered: u64,
+    pub read: u64,
+}
+
+/// A message that receipts can be sent for
+pub struct Sent {
+    pub seq: u64,
+    pub id: u64,
+    pub sender: usize,
+}
+
+/// A member that can not post until the given time. It is held on the
+/// name and IP like a ban, so reconnecting does not lift it.
+pub struct Mute {
+    /// The session muted, until it disconnects
+    pub id: Option<usize>,
+    pub who: Ban,
+    pub until: Instant,
+}
+
+/// A chat room, its members and who else may get in
+#[derive(Default)]
+pub struct Room {
+    pub members: HashSet<usize>,
+    /// Kept around while empty
+    pub persistent: bool,
+    /// Hidden from `/list`, only invited sessions can join
+    pub private: bool,
+    pub password: Option<String>,
+    /// Sessions invited by a member, an invitation is used up on join
+    pub invited: HashSet<usize>,
+    /// Sessions allowed to moderate the room, starting with whoever created it
+    pub ops: HashSet<usize>,
+    pub muted: Vec<Mute>,
+    /// When the sweeper first saw the room empty
+    pub empty_since: Option<Instant>,
+    /// Recent messages, oldest first
+    pub sent: VecDeque<Sent>,
+    pub acks: HashMap<usize, Acks>,
+}
+
+impl Room {
+    /// Take a session out of the room, a mute still applies if it comes back
+    pub fn leave(&mut self, id: usize) -> bool {
+        self.ops.remove(&id);
+        self.acks.remove(&id);
+        self.members.remove(&id)
+    }
+
+    /// Forget everything the room knows about a disconnected session, but
+    /// for mutes on its name or IP
+    pub fn forget(&mut self, id: usize) -> bool {
+        self.invited.remove(&id);
+        for mute in &mut self.muted {
+            if mute.id == Some(id) {
+                mute.id = None;
+            }
+        }
+        self.muted
+            .retain(|mute| mute.id.is_some() || mute.who.name.is_some() || mute.who.ip.is_some());
+        self.leave(id)
+    }
+
+    pub fn mute(&mut self, mute: Mute) {
+        let now = Instant::now();
+        self.muted.retain(|mute| mute.until > now);
+        self.muted.push(mute);
+    }
+
+    pub fn unmute(&mut self, id: usize, name: Option<&str>, ip: Option<&str>) {
+        self.muted
+            .retain(|mute| mute.id != Some(id) && !mute.who.matches(name, ip));
+    }
+
+    /// How much longer a session is muted for, if it is
+    pub fn muted_for(&self, id: usize, name: Option<&str>, ip: Option<&str>) -> Option<Duration> {
+        let now = Instant::now();
+        self.muted
+            .iter()
+            .filter(|mute| mute.id == Some(id) || mute.who.matches(name, ip))
+            .filter_map(|mute| mute.until.checked_duration_since(now))
+            .max()
+    }
+
+    pub fn persistent() -> Room {
+        Room {
+            persistent: true,
+            ..Room::default()
+        }
+    }
+
+    pub fn record(&mut self, sent: Sent) {
+        self.sent.push_back(sent);
+        while self.sent.len() > RECEIPT_WINDOW {
+            self.sent.pop_front();
+        }
+    }
+
+    /// Move the acknowledgement of a member up to `seq`, reading a message
+    /// also counts as getting it. Returns the messages newly acknowledged,
+    /// leaving out the member's own.
+    pub fn ack(&mut self, id: usize, seq: u64, read: bool) -> Vec<&Sent> {
+        let acks = self.acks.entry(id).or_default();
+        let prev = if read { acks.read } else { acks.delivered };
+        if seq <= prev {
+            return Vec::new();
+        }
+        if read {
+            acks.read = seq;
+        }
+        acks.delivered = acks.delivered.max(seq);
+
+        self.sent
+            .iter()
+            .filter(|sent| sent.seq > prev && sent.seq <= seq && sent.sender != id)
+            .collect()
+    }
+
+    /// Check whether a session may join the room.
+    ///
+    /// An invitation lets the session past both the private flag and the
+    /// password.
+    pub fn admit(&mut self, id: usize, password: Option<&str>) -> Result<(), String> {
+        if self.members.contains(&id) || self.invited.remove(&id) {
+            return Ok(());
+        }
+        if self.private {
+            return Err(String::from("room is invite only"));
+        }
+        match self.password {
+            Some(ref expected) if password != Some(expected.as_str()) => {
+                Err(String::from("wrong password"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -1,21 +1,73 @@
 use actix::prelude::*;
 use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use rand::{ self, rngs::ThreadRng, Rng };
+use serde::Serialize;
+
+use crate::bans::{Ban, Bans};
+use crate::cluster::{ClusterConfig, Link, Publish, Relay, Relayed, Subscribed, Update};
+use crate::history::History;
+use crate::metrics::{Metrics, Snapshot};
+use crate::outbox::{Backpressure, Outbox, SlowConsumer};
+use crate::protocol::{Event, Member, Presence, Stamp};
+use crate::ratelimit::{Limiter, RateLimits, Verdict};
+use crate::room::{Mute, Room, Sent};
+
+/// The lobby every session joins on connect, it is never removed
+pub const MAIN_ROOM: &str = "Main";
+/// How long an upload ticket can be used after it was issued
+const UPLOAD_TICKET_TTL: Duration = Duration::from_secs(300);
+/// A typing indicator goes away if it is not renewed for this long
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+const TYPING_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// How often queued events are pushed on to sessions that fell behind
+const OUTBOX_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+/// How long an empty room is kept around before it is removed
+const ROOM_GRACE: Duration = Duration::from_secs(60);
+/// How long a dropped session can be resumed by default
+pub const RESUME_GRACE: Duration = Duration::from_secs(60);
+/// Events held for a dropped session, older ones are dropped first
+const MISSED_LIMIT: usize = 500;
+const ROOM_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
 
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct Message(pub String);
+pub struct Message(pub Event);
 
 #[derive(Message)]
-#[rtype(result = "usize")]
+#[rtype(result = "Connected")]
 pub struct Connect {
     pub addr: Recipient<Message>,
+    /// Address of the client, used for IP bans
+    pub ip: Option<String>,
+    /// Token of a dropped session to pick up again
+    pub resume: Option<String>,
+    /// Who the connection authenticated as, this is the session's name
+    pub user: Option<String>,
+}
+
+/// The session a connection got, either a new one or a resumed one
+pub struct Connected {
+    pub id: usize,
+    /// Tells `Disconnect`s of an old connection of the session apart
+    pub conn: u64,
+    pub token: String,
+    pub resumed: bool,
+    pub name: Option<String>,
+    /// Rooms the session is in and the one it was talking in
+    pub rooms: Vec<String>,
+    pub active: Option<String>,
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Disconnect {
     pub id: usize,
+    pub conn: u64,
+    /// Hold the session for a resume, remembering the room it was talking
+    /// in. Without it the session is gone at once.
+    pub active: Option<String>,
 }
 
 #[derive(Message)]
@@ -30,53 +82,560 @@ pub struct ClientMessage {
 #[rtype(result = "Vec<String>")]
 pub struct ListRooms;
 
+/// List the members of a room, unnamed sessions are listed by id
 #[derive(Message)]
-#[rtype(result = "()")]
+#[rtype(result = "Result<Vec<Member>, String>")]
+pub struct ListMembers {
+    pub id: usize,
+    pub room: String,
+}
+
+/// Join a room, on top of the rooms the session is already in.
+///
+/// A room that does not exist yet is created, with `password` if one is given.
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
 pub struct Join {
     pub id: usize,
     pub room: String,
+    pub password: Option<String>,
+}
+
+/// Leave a single room, the session stays in its other rooms
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct Leave {
+    pub id: usize,
+    pub room: String,
+}
+
+/// Claim a nickname for the session, fails if somebody else already holds it
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct SetName {
+    pub id: usize,
+    pub name: String,
+}
+
+/// Send a message to a single session, addressed by its name
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct PrivateMessage {
+    pub id: usize,
+    pub to: String,
+    pub msg: String,
+}
+
+/// Let someone else into a room the session is in
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct Invite {
+    pub id: usize,
+    pub name: String,
+    pub room: String,
+}
+
+/// Change the access rules of a room, `None` keeps the current setting.
+/// An empty password removes it.
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct Configure {
+    pub id: usize,
+    pub room: String,
+    pub private: Option<bool>,
+    pub password: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct Typing {
+    pub id: usize,
+    pub room: String,
+    pub typing: bool,
+}
+
+/// Presence picked by the user, `Online` also ends being idle
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct SetPresence {
+    pub id: usize,
+    pub presence: Presence,
+    pub message: Option<String>,
+}
+
+/// The session noticed its user went idle or came back. Unlike
+/// `SetPresence` this never overrides being away.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Idle {
+    pub id: usize,
+    pub idle: bool,
+}
+
+/// Acknowledge the messages of a room up to `seq`, see `Command::Ack`
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct Ack {
+    pub id: usize,
+    pub room: String,
+    pub seq: u64,
+    pub read: bool,
+}
+
+/// Send the kept messages of a room after `since` to the session
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct Replay {
+    pub id: usize,
+    pub room: String,
+    pub since: u64,
+}
+
+/// Counters for monitoring. Sessions asking need to be server admins.
+#[derive(Message)]
+#[rtype(result = "Result<Stats, String>")]
+pub struct GetStats {
+    pub id: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub sessions: usize,
+    pub rooms: usize,
+    /// Events waiting in outboxes
+    pub queued: usize,
+    /// Events dropped because sessions did not keep up
+    pub dropped: u64,
+    /// Sessions disconnected for not keeping up
+    pub slow_disconnects: u64,
+}
+
+/// Everything there is to monitor, in the Prometheus text format
+#[derive(Message)]
+#[rtype(result = "String")]
+pub struct GetMetrics;
+
+/// A session stopped answering pings
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct HeartbeatTimeout;
+
+/// Ask for a single-use ticket to upload files into a room
+#[derive(Message)]
+#[rtype(result = "Result<String, String>")]
+pub struct UploadTicket {
+    pub id: usize,
+    pub room: String,
+}
+
+/// Trade an upload ticket for the session and room it was issued for
+#[derive(Message)]
+#[rtype(result = "Result<(usize, String), String>")]
+pub struct RedeemUpload {
+    pub ticket: String,
+}
+
+/// A file was uploaded, let the room know where to get it
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ShareFile {
+    pub id: usize,
+    pub room: String,
+    pub name: String,
+    pub size: usize,
+    pub url: String,
+}
+
+/// May the session holding the token fetch the files shared in the room
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct FileAccess {
+    pub token: String,
+    pub room: String,
+}
+
+#[derive(Debug)]
+pub enum Action {
+    Kick,
+    Ban,
+    Unban,
+    Mute(Duration),
+    Op,
+}
+
+/// A moderation request from a room operator, `target` is a name or an IP
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct Moderate {
+    pub id: usize,
+    pub room: String,
+    pub target: String,
+    pub action: Action,
+}
+
+/// Close every connection, telling it why
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shutdown {
+    pub message: String,
+}
+
+/// Make sure everything kept on disk is written out
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Flush;
+
+/// Every room with its member count, for the admin API
+#[derive(Message)]
+#[rtype(result = "Vec<RoomInfo>")]
+pub struct AdminRooms;
+
+#[derive(Debug, Serialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub members: usize,
+    pub private: bool,
+    pub persistent: bool,
+}
+
+/// The sessions in a room, for the admin API
+#[derive(Message)]
+#[rtype(result = "Result<Vec<SessionInfo>, String>")]
+pub struct AdminMembers {
+    pub room: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: usize,
+    pub name: Option<String>,
+    pub ip: Option<String>,
+    pub presence: Presence,
+    /// Waiting for a resume
+    pub detached: bool,
+    pub op: bool,
+}
+
+/// A message from the server itself, posted into a room
+#[derive(Message)]
+#[rtype(result = "Result<Stamp, String>")]
+pub struct SystemBroadcast {
+    pub room: String,
+    pub text: String,
+}
+
+/// Close a session's connection and forget it, no resume
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct ForceDisconnect {
+    pub id: usize,
+    pub reason: String,
+}
+
+/// Turn everyone out of a room and remove it, deleting its history. The
+/// rooms of `rooms.txt` are there for good and can not be closed.
+#[derive(Message)]
+#[rtype(result = "Result<(), CloseRefused>")]
+pub struct CloseRoom {
+    pub room: String,
+}
+
+/// Why a room was not closed
+#[derive(Debug)]
+pub enum CloseRefused {
+    NoSuchRoom(String),
+    Permanent(String),
+}
+
+impl fmt::Display for CloseRefused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseRefused::NoSuchRoom(room) => write!(f, "no such room: {}", room),
+            CloseRefused::Permanent(room) => write!(f, "{} is a permanent room", room),
+        }
+    }
+}
+
+struct Session {
+    /// Gone while the connection is down and the session waits for a resume
+    addr: Option<Recipient<Message>>,
+    conn: u64,
+    token: String,
+    /// When the connection dropped
+    detached: Option<Instant>,
+    /// Events that did not fit the mailbox, or came in while detached
+    outbox: Outbox,
+    active: Option<String>,
+    name: Option<String>,
+    /// The name is the identity the connection authenticated as
+    verified: bool,
+    ip: Option<String>,
+    limiter: Limiter,
+    presence: Presence,
+    away_message: Option<String>,
+    /// Rooms the session is typing in, and when it last said so
+    typing: HashMap<String, Instant>,
+}
+
+impl Session {
+    /// A fresh session, detached from the start when there is no connection
+    fn new(addr: Option<Recipient<Message>>, conn: u64, token: String, limits: &RateLimits) -> Session {
+        Session {
+            detached: if addr.is_none() { Some(Instant::now()) } else { None },
+            addr,
+            conn,
+            token,
+            outbox: Outbox::default(),
+            active: None,
+            name: None,
+            verified: false,
+            ip: None,
+            limiter: Limiter::new(limits),
+            presence: Presence::Online,
+            away_message: None,
+            typing: HashMap::new(),
+        }
+    }
+
+    /// Deliver an event, queueing it while the mailbox is full or the
+    /// session is detached
+    fn send(&mut self, event: Event, backpressure: &Backpressure) {
+        if self.outbox.is_empty() {
+            let addr = match self.addr {
+                Some(ref addr) => addr,
+                None => return self.outbox.push(event, MISSED_LIMIT, SlowConsumer::DropOldest),
+            };
+            match addr.try_send(Message(event)) {
+                Ok(()) => (),
+                Err(SendError::Full(Message(event))) => {
+                    self.outbox.push(event, backpressure.capacity, backpressure.policy)
+                }
+                Err(SendError::Closed(Message(event))) => {
+                    self.detach();
+                    self.outbox.push(event, MISSED_LIMIT, SlowConsumer::DropOldest)
+                }
+            }
+        } else if self.addr.is_some() {
+            // Keep the order, whatever is queued goes first
+            self.outbox.push(event, backpressure.capacity, backpressure.policy)
+        } else {
+            self.outbox.push(event, MISSED_LIMIT, SlowConsumer::DropOldest)
+        }
+    }
+
+    /// Move queued events into the mailbox while there is room
+    fn flush(&mut self) {
+        while let Some(ref addr) = self.addr {
+            let event = match self.outbox.pop() {
+                Some(event) => event,
+                None => return,
+            };
+            match addr.try_send(Message(event)) {
+                Ok(()) => (),
+                Err(SendError::Full(Message(event))) => return self.outbox.unpop(event),
+                Err(SendError::Closed(Message(event))) => {
+                    self.outbox.unpop(event);
+                    self.detach();
+                }
+            }
+        }
+    }
+
+    /// Let go of the connection, keeping the session for a resume
+    fn detach(&mut self) {
+        self.addr = None;
+        if self.detached.is_none() {
+            self.detached = Some(Instant::now());
+        }
+    }
 }
 
 pub struct ChatServer {
-    sessions: HashMap<usize, Recipient<Message>>,
-    rooms: HashMap<String, HashSet<usize>>,
+    sessions: HashMap<usize, Session>,
+    rooms: HashMap<String, Room>,
+    /// Names that operate every room
+    admins: HashSet<String>,
+    bans: Bans,
+    limits: RateLimits,
+    /// Outstanding upload tickets: session, room and when it was issued
+    uploads: HashMap<String, (usize, String, Instant)>,
     rng: ThreadRng,
+    /// Next session id to hand out, ids are never reused while in use
+    next_id: usize,
+    history: History,
+    /// Id of the next room message
+    next_message_id: u64,
+    /// Resume tokens of the sessions
+    tokens: HashMap<String, usize>,
+    next_conn: u64,
+    resume_grace: Duration,
+    backpressure: Backpressure,
+    /// Counters of sessions that are gone, live sessions are added on top
+    stats: Stats,
+    metrics: Metrics,
+    /// Set until the links to the other nodes are started
+    cluster: Option<ClusterConfig>,
+    /// Names this process in the cluster
+    node: String,
+    publisher: Option<Addr<Link>>,
+    /// Who may join the private and password rooms of the cluster, a room
+    /// created here starts out the way it is set up on the other nodes
+    cluster_rooms: HashMap<String, (bool, Option<String>)>,
+    /// Set once the server is going down, nothing is held for a resume then
+    shutting_down: bool,
 }
 
-impl Default for ChatServer {
-    fn default() -> ChatServer {
-        let mut rooms = HashMap::new();
-        rooms.insert(String::from("Main"), HashSet::new());
+impl ChatServer {
+    /// Create a server with pre-declared rooms, which are never removed,
+    /// the names of the server admins, and where bans and history are kept
+    pub fn new(persistent: Vec<String>, admins: Vec<String>, bans: Bans, history: History) -> ChatServer {
+        let mut rooms: HashMap<String, Room> = persistent
+            .into_iter()
+            .map(|room| (room, Room::persistent()))
+            .collect();
+        rooms.insert(String::from(MAIN_ROOM), Room::persistent());
 
         ChatServer {
             sessions: HashMap::new(),
             rooms,
+            admins: admins.into_iter().collect(),
+            bans,
+            limits: RateLimits::default(),
+            uploads: HashMap::new(),
             rng: rand::thread_rng(),
+            next_id: 1,
+            history,
+            // Start from the clock so ids stay unique across restarts
+            next_message_id: unix_millis() * 1000,
+            tokens: HashMap::new(),
+            next_conn: 0,
+            resume_grace: RESUME_GRACE,
+            backpressure: Backpressure::default(),
+            stats: Stats::default(),
+            metrics: Metrics::default(),
+            cluster: None,
+            node: String::new(),
+            publisher: None,
+            cluster_rooms: HashMap::new(),
+            shutting_down: false,
         }
     }
 }
 
+impl ChatServer {
+    /// Replace the default flood protection settings
+    pub fn rate_limits(mut self, limits: RateLimits) -> ChatServer {
+        self.limits = limits;
+        self
+    }
+
+    /// Replace the default outbound queue settings
+    pub fn backpressure(mut self, backpressure: Backpressure) -> ChatServer {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Share rooms with the other servers on the same broker
+    pub fn cluster(mut self, config: ClusterConfig) -> ChatServer {
+        self.cluster = Some(config);
+        self
+    }
+
+    /// How long a dropped session can be resumed, zero turns resuming off
+    pub fn resume_grace(mut self, grace: Duration) -> ChatServer {
+        self.resume_grace = grace;
+        self
+    }
+}
+
 impl Actor for ChatServer {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(ROOM_SWEEP_INTERVAL, |act, _| {
+            act.sweep_detached();
+            act.sweep_rooms();
+        });
+        ctx.run_interval(TYPING_SWEEP_INTERVAL, |act, _| act.sweep_typing());
+        ctx.run_interval(OUTBOX_FLUSH_INTERVAL, |act, _| act.flush_outboxes());
+
+        if let Some(config) = self.cluster.take() {
+            self.node = format!("{:016x}", self.rng.gen::<u64>());
+            println!("Joining the cluster on {} as node {}", config.broker, self.node);
+            let server = ctx.address();
+            Link::subscriber(config.clone(), self.node.clone(), server.clone().recipient(), server.recipient())
+                .start();
+            self.publisher = Some(Link::publisher(config, self.node.clone()).start());
+        }
+    }
 }
 
 impl Handler<Connect> for ChatServer {
-    type Result = usize;
+    type Result = MessageResult<Connect>;
 
     fn handle(&mut self, msg: Connect, _: &mut Self::Context) -> Self::Result {
-        println!("Someone joined lobby");
-        self.send_message(&"Main", "Someone joined lobby", 0);
+        if self.shutting_down {
+            // Got in just before the doors closed, it is let go once connected
+            let message = String::from("server is shutting down");
+            let _ = msg.addr.do_send(Message(Event::Shutdown { message }));
+        }
+        self.next_conn += 1;
+        let conn = self.next_conn;
+        let resumable = msg
+            .resume
+            .as_ref()
+            .and_then(|token| self.tokens.get(token).copied())
+            // A verified session is only for the same user
+            .filter(|id| msg.user.is_none() || self.name(*id) == msg.user.as_deref());
+        if let Some(id) = resumable {
+            return MessageResult(self.resume(id, conn, msg.addr));
+        }
+
         // Adding a new entry into sessions table
-        let id = self.rng.gen::<usize>();
-        self.sessions.insert(id, msg.addr);
+        let id = self.next_session_id();
+        let token = format!("{:016x}{:016x}", self.rng.gen::<u64>(), self.rng.gen::<u64>());
+        self.tokens.insert(token.clone(), id);
+        let mut session = Session::new(Some(msg.addr), conn, token.clone(), &self.limits);
+        session.name = msg.user.clone();
+        session.verified = msg.user.is_some();
+        session.ip = msg.ip.clone();
+        self.sessions.insert(id, session);
 
-        // automatically join the main room
-        self.rooms
-            .entry(String::from("Main"))
-            .or_insert(HashSet::new())
-            .insert(id);
+        // automatically join the main room, unless banned from it
+        let name = msg.user.clone().unwrap_or_else(|| String::from("Someone"));
+        let mut rooms = Vec::new();
+        if self.bans.is_banned(MAIN_ROOM, msg.user.as_deref(), msg.ip.as_deref()) {
+            println!("{} is banned from the lobby", name);
+            let message = format!("you are banned from {}", MAIN_ROOM);
+            self.send_to(id, Event::Error { message });
+        } else {
+            println!("{} joined lobby", name);
+            self.send_message(
+                MAIN_ROOM,
+                Event::Join {
+                    room: String::from(MAIN_ROOM),
+                    name,
+                },
+                None,
+            );
+            self.rooms
+                .entry(String::from(MAIN_ROOM))
+                .or_insert_with(Room::persistent)
+                .members
+                .insert(id);
+            self.replay_history(MAIN_ROOM, id);
+            rooms.push(String::from(MAIN_ROOM));
+        }
 
-        id
+        MessageResult(Connected {
+            id,
+            conn,
+            token,
+            resumed: false,
+            name: msg.user,
+            rooms,
+            active: None,
+        })
     }
 
 }
@@ -85,20 +644,24 @@ impl Handler<Disconnect> for ChatServer {
     type Result = ();
 
     fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) -> Self::Result {
-        println!("Someone disconnected");
-
-        let mut rooms: Vec<String> = Vec::new();
-
-        if self.sessions.remove(&msg.id).is_some() {
-            for (name, ids) in &mut self.rooms {
-                if ids.remove(&msg.id) {
-                    rooms.push(String::from(name));
+        let grace = self.resume_grace;
+        let session = match self.sessions.get_mut(&msg.id) {
+            Some(session) if session.conn == msg.conn => session,
+            // A connection that was replaced by a resume, or is already gone
+            _ => return,
+        };
+        match msg.active {
+            Some(active) if grace > Duration::from_secs(0) && !self.shutting_down => {
+                // Sending to it may have noticed the connection is gone first
+                session.detach();
+                session.active = Some(active);
+                let rooms: Vec<String> = session.typing.keys().cloned().collect();
+                println!("{} dropped, holding the session", self.display_name(msg.id));
+                for room in rooms {
+                    self.stop_typing(msg.id, &room);
                 }
             }
-        }
-
-        for room in &rooms {
-            self.send_message(room, "Someone disconnected", 0);
+            _ => self.remove_session(msg.id),
         }
     }
 }
@@ -108,36 +671,112 @@ impl Handler<ListRooms> for ChatServer {
 
     fn handle(&mut self, _: ListRooms, _: &mut Self::Context) -> Self::Result {
         let mut rooms: Vec<String> = Vec::new();
-        for (room, _) in &self.rooms {
-            rooms.push(String::from(room));
+        for (name, room) in &self.rooms {
+            if !room.private {
+                rooms.push(String::from(name));
+            }
         }
         MessageResult(rooms)
     }
 }
 
+impl Handler<ListMembers> for ChatServer {
+    type Result = Result<Vec<Member>, String>;
+
+    fn handle(&mut self, msg: ListMembers, _: &mut Self::Context) -> Self::Result {
+        // Private rooms do not exist for outsiders
+        let room = self
+            .rooms
+            .get(&msg.room)
+            .filter(|room| !room.private || room.members.contains(&msg.id))
+            .ok_or_else(|| format!("no such room: {}", msg.room))?;
+        let mut members: Vec<Member> = room
+            .members
+            .iter()
+            .filter_map(|id| self.sessions.get(id).map(|session| (id, session)))
+            .map(|(id, session)| Member {
+                name: match session.name {
+                    Some(ref name) => name.clone(),
+                    None => format!("#{}", id),
+                },
+                presence: session.presence,
+                message: session.away_message.clone(),
+            })
+            .collect();
+        members.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(members)
+    }
+}
+
 impl Handler<Join> for ChatServer {
-    type Result = ();
+    type Result = Result<(), String>;
 
     fn handle(&mut self, msg: Join, _: &mut Self::Context) -> Self::Result {
-        let Join { id, room } = msg;
-        let mut rooms: Vec<String> = Vec::new();
+        let Join { id, room, password } = msg;
+        let name = self.display_name(id);
 
-        for (n, ids) in &mut self.rooms {
-            if ids.remove(&id) {
-                rooms.push(String::from(n));
-            }
+        let session = self.sessions.get(&id);
+        let banned = self.bans.is_banned(
+            &room,
+            session.and_then(|s| s.name.as_deref()),
+            session.and_then(|s| s.ip.as_deref()),
+        );
+        if banned {
+            return Err(format!("you are banned from {}", room));
         }
 
-        for r in &rooms {
-            self.send_message(r, "Someone left", 0);
+        let (private, password_set) = match self.cluster_rooms.get(&room) {
+            Some((private, password)) => (*private, password.clone()),
+            None => (false, password.clone().filter(|password| !password.is_empty())),
+        };
+        let created = !self.rooms.contains_key(&room);
+        let entry = self.rooms.entry(String::from(&room)).or_insert_with(|| Room {
+            private,
+            password: password_set,
+            // Whoever creates a room owns it
+            ops: vec![id].into_iter().collect(),
+            ..Room::default()
+        });
+        entry
+            .admit(id, password.as_deref())
+            .map_err(|e| format!("can not join {}: {}", room, e))?;
+        if !entry.members.insert(id) {
+            return Ok(());
+        }
+        if created && entry.password.is_some() {
+            self.share_room(&room);
         }
 
-        self.rooms
-            .entry(String::from(&room))
-            .or_insert(HashSet::new())
-            .insert(msg.id);
+        let event = Event::Join {
+            room: room.clone(),
+            name,
+        };
+        self.send_message(&room, event, Some(id));
+        self.replay_history(&room, id);
+        Ok(())
+    }
+}
+
+impl Handler<Leave> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Leave, _: &mut Self::Context) -> Self::Result {
+        let Leave { id, room } = msg;
+        self.stop_typing(id, &room);
+        let left = self
+            .rooms
+            .get_mut(&room)
+            .is_some_and(|r| r.leave(id));
+        if !left {
+            return Err(format!("you are not in {}", room));
+        }
 
-        self.send_message(&room, "Someone joined", id);
+        let event = Event::Leave {
+            room: room.clone(),
+            name: self.display_name(id),
+        };
+        self.send_message(&room, event, None);
+        Ok(())
     }
 }
 
@@ -145,21 +784,1530 @@ impl Handler<ClientMessage> for ChatServer {
     type Result = ();
 
     fn handle(&mut self, msg: ClientMessage, _: &mut Self::Context) -> Self::Result {
-        self.send_message(&msg.room, &msg.msg, msg.id);
+        if !self.is_member(msg.id, &msg.room) {
+            let message = format!("you are not in {}", msg.room);
+            self.send_to(msg.id, Event::Error { message });
+            return;
+        }
+        if let Err(message) = self.check_flood(msg.id, msg.msg.len()) {
+            self.send_to(msg.id, Event::Error { message });
+            return;
+        }
+        let session = &self.sessions[&msg.id];
+        let muted = self.rooms[&msg.room].muted_for(
+            msg.id,
+            session.name.as_deref(),
+            session.ip.as_deref(),
+        );
+        if let Some(left) = muted {
+            let message = format!(
+                "you are muted in {} for another {}s",
+                msg.room,
+                left.as_secs() + 1
+            );
+            self.send_to(msg.id, Event::Error { message });
+            return;
+        }
+
+        // Sending the message is the end of typing it
+        self.stop_typing(msg.id, &msg.room);
+
+        let stamp = self.stamp(&msg.room, msg.id);
+        let event = Event::Message {
+            room: msg.room.clone(),
+            from: self.name(msg.id).map(str::to_owned),
+            text: msg.msg,
+            stamp,
+        };
+        self.history.append(&msg.room, &event);
+        self.send_message(&msg.room, event, Some(msg.id));
+        self.send_to(msg.id, Event::Sent { room: msg.room, stamp });
+        self.metrics.messages_sent += 1;
     }
 }
 
+impl Handler<SetName> for ChatServer {
+    type Result = Result<(), String>;
 
-impl ChatServer {
-    fn send_message(&self, room: &str, msg: &str, skip_id: usize) {
-        if let Some(sessions) = self.rooms.get(room) {
-            for session_id in sessions {
-                if *session_id != skip_id {
-                    if let Some(recipient) = self.sessions.get(session_id) {
-                        let _ = recipient.do_send(Message(String::from(msg)));
-                    }
+    fn handle(&mut self, msg: SetName, _: &mut Self::Context) -> Self::Result {
+        let SetName { id, name } = msg;
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(String::from("name can not contain spaces"));
+        }
+        if self.name(id) == Some(name.as_str()) {
+            return Ok(());
+        }
+        if self.sessions.get(&id).is_some_and(|session| session.verified) {
+            return Err(String::from("your name comes from your login"));
+        }
+        let taken = self.sessions.iter().any(|(other, s)| {
+            *other != id && s.name.as_ref().is_some_and(|n| same_name(n, &name))
+        });
+        if taken {
+            return Err(format!("name {} is already taken", name));
+        }
+        self.check_flood(id, name.len())?;
+
+        let old = self.display_name(id);
+        match self.sessions.get_mut(&id) {
+            Some(session) => session.name = Some(name.clone()),
+            None => return Err(String::from("not connected")),
+        }
+
+        println!("{} is now known as {}", old, name);
+        self.send_to_peers(id, Event::Rename { old, new: name }, false);
+        Ok(())
+    }
+}
+
+impl Handler<PrivateMessage> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: PrivateMessage, _: &mut Self::Context) -> Self::Result {
+        let PrivateMessage { id, to, msg } = msg;
+        self.check_flood(id, msg.len())?;
+        let to_id = self
+            .find_session(&to)
+            .ok_or_else(|| format!("{} is not online", to))?;
+
+        let from = self.display_name(id);
+        self.send_to(to_id, Event::Private { from, text: msg });
+        Ok(())
+    }
+}
+
+impl Handler<Invite> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Invite, _: &mut Self::Context) -> Self::Result {
+        let Invite { id, name, room } = msg;
+        if !self.is_member(id, &room) {
+            return Err(format!("you are not in {}", room));
+        }
+        let to_id = self
+            .find_session(&name)
+            .ok_or_else(|| format!("{} is not online", name))?;
+        self.check_flood(id, 0)?;
+
+        if let Some(r) = self.rooms.get_mut(&room) {
+            r.invited.insert(to_id);
+        }
+        let event = Event::Invite {
+            room,
+            from: self.display_name(id),
+        };
+        self.send_to(to_id, event);
+        Ok(())
+    }
+}
+
+impl Handler<Configure> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Configure, _: &mut Self::Context) -> Self::Result {
+        let Configure { id, room, private, password } = msg;
+        if room == MAIN_ROOM {
+            return Err(format!("{} is always public", MAIN_ROOM));
+        }
+        if !self.is_member(id, &room) {
+            return Err(format!("you are not in {}", room));
+        }
+        if !self.is_op(id, &room) {
+            return Err(format!("you are not an operator of {}", room));
+        }
+
+        let r = self.rooms.get_mut(&room).unwrap();
+        if let Some(private) = private {
+            r.private = private;
+        }
+        if let Some(password) = password {
+            r.password = Some(password).filter(|password| !password.is_empty());
+        }
+        let event = Event::RoomSettings {
+            room: room.clone(),
+            private: r.private,
+            password: r.password.is_some(),
+        };
+        self.send_message(&room, event, None);
+        self.share_room(&room);
+        Ok(())
+    }
+}
+
+impl Handler<Moderate> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Moderate, _: &mut Self::Context) -> Self::Result {
+        let Moderate { id, room, target, action } = msg;
+        if !self.rooms.contains_key(&room) {
+            return Err(format!("no such room: {}", room));
+        }
+        if !self.is_op(id, &room) {
+            return Err(format!("you are not an operator of {}", room));
+        }
+        let by = self.display_name(id);
+        let target_id = self.find_session(&target);
+        if target_id.is_some_and(|target_id| self.is_admin(target_id)) {
+            return Err(format!("{} is an admin", target));
+        }
+        println!("{} {:?} {} in {}", by, action, target, room);
+
+        let event = match action {
+            Action::Kick => {
+                let target_id = target_id
+                    .filter(|target_id| self.is_member(*target_id, &room))
+                    .ok_or_else(|| format!("{} is not in {}", target, room))?;
+                self.send_message(
+                    &room,
+                    Event::Kick {
+                        room: room.clone(),
+                        by,
+                        name: target,
+                    },
+                    None,
+                );
+                self.remove_from_room(target_id, &room);
+                return Ok(());
+            }
+            Action::Ban => {
+                let ban = match target_id.and_then(|target_id| self.sessions.get(&target_id)) {
+                    Some(session) => Ban {
+                        name: session.name.clone(),
+                        ip: session.ip.clone(),
+                    },
+                    None if target.parse::<std::net::IpAddr>().is_ok() => Ban {
+                        name: None,
+                        ip: Some(target.clone()),
+                    },
+                    // Not online, the name alone is all we can ban
+                    None => Ban {
+                        name: Some(target.clone()),
+                        ip: None,
+                    },
+                };
+                self.bans.add(&room, ban.clone());
+                self.share(Update::Ban {
+                    room: room.clone(),
+                    bans: vec![ban],
+                });
+                self.send_message(
+                    &room,
+                    Event::Ban {
+                        room: room.clone(),
+                        by,
+                        name: target,
+                    },
+                    None,
+                );
+                if let Some(target_id) = target_id {
+                    self.remove_from_room(target_id, &room);
+                }
+                return Ok(());
+            }
+            Action::Unban => {
+                if !self.bans.remove(&room, &target) {
+                    return Err(format!("{} is not banned from {}", target, room));
+                }
+                self.share(Update::Unban {
+                    room: room.clone(),
+                    target: target.clone(),
+                });
+                Event::Unban {
+                    room: room.clone(),
+                    by,
+                    name: target,
+                }
+            }
+            Action::Mute(duration) => {
+                let target_id = target_id
+                    .filter(|target_id| self.is_member(*target_id, &room))
+                    .ok_or_else(|| format!("{} is not in {}", target, room))?;
+                let session = &self.sessions[&target_id];
+                let mute = Mute {
+                    id: Some(target_id),
+                    who: Ban {
+                        name: session.name.clone(),
+                        ip: session.ip.clone(),
+                    },
+                    until: Instant::now() + duration,
+                };
+                self.rooms.get_mut(&room).unwrap().mute(mute);
+                Event::Mute {
+                    room: room.clone(),
+                    by,
+                    name: target,
+                    seconds: duration.as_secs(),
+                }
+            }
+            Action::Op => {
+                let target_id = target_id
+                    .filter(|target_id| self.is_member(*target_id, &room))
+                    .ok_or_else(|| format!("{} is not in {}", target, room))?;
+                let session = &self.sessions[&target_id];
+                let r = self.rooms.get_mut(&room).unwrap();
+                r.ops.insert(target_id);
+                r.unmute(target_id, session.name.as_deref(), session.ip.as_deref());
+                Event::Op {
+                    room: room.clone(),
+                    by,
+                    name: target,
                 }
             }
+        };
+        self.send_message(&room, event, None);
+        Ok(())
+    }
+}
+
+impl Handler<Typing> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Typing, _: &mut Self::Context) -> Self::Result {
+        let Typing { id, room, typing } = msg;
+        if !self.is_member(id, &room) {
+            return Err(format!("you are not in {}", room));
+        }
+        // Only a change goes out to the room, repeats cost nothing
+        let was_typing = self
+            .sessions
+            .get(&id)
+            .is_some_and(|session| session.typing.contains_key(&room));
+        if typing != was_typing {
+            self.check_flood(id, 0)?;
+        }
+        if !typing {
+            self.stop_typing(id, &room);
+            return Ok(());
+        }
+
+        let started = match self.sessions.get_mut(&id) {
+            Some(session) => session.typing.insert(room.clone(), Instant::now()).is_none(),
+            None => return Err(String::from("not connected")),
+        };
+        // Only the first notice is broadcast, repeats just keep it alive
+        if started {
+            let event = Event::Typing {
+                room: room.clone(),
+                name: self.display_name(id),
+                typing: true,
+            };
+            self.send_message(&room, event, Some(id));
         }
+        Ok(())
+    }
+}
+
+impl Handler<Ack> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Ack, _: &mut Self::Context) -> Self::Result {
+        let Ack { id, room, seq, read } = msg;
+        let name = self.display_name(id);
+        let receipts: Vec<(usize, Event)> = match self.rooms.get_mut(&room) {
+            Some(state) if state.members.contains(&id) => state
+                .ack(id, seq, read)
+                .into_iter()
+                .map(|sent| {
+                    let event = Event::Receipt {
+                        room: room.clone(),
+                        id: sent.id,
+                        seq: sent.seq,
+                        name: name.clone(),
+                        read,
+                    };
+                    (sent.sender, event)
+                })
+                .collect(),
+            _ => return Err(format!("you are not in {}", room)),
+        };
+        for (sender, event) in receipts {
+            self.send_to(sender, event);
+        }
+        Ok(())
+    }
+}
+
+impl Handler<Replay> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Replay, _: &mut Self::Context) -> Self::Result {
+        let Replay { id, room, since } = msg;
+        if !self.is_member(id, &room) {
+            return Err(format!("you are not in {}", room));
+        }
+        let events: Vec<Event> = self
+            .history
+            .replay(&room)
+            .into_iter()
+            .filter(|event| event.stamp().is_some_and(|stamp| stamp.seq > since))
+            .collect();
+        // Only the last few messages are kept, tell the client what it will not get
+        let first = events.first().and_then(Event::stamp).map(|stamp| stamp.seq);
+        if let Some(first) = first {
+            if first > since + 1 {
+                let message = format!(
+                    "messages {} to {} of {} are no longer available",
+                    since + 1,
+                    first - 1,
+                    room
+                );
+                self.send_to(id, Event::Error { message });
+            }
+        }
+        for event in events {
+            self.send_to(id, event);
+        }
+        Ok(())
+    }
+}
+
+impl Handler<SetPresence> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: SetPresence, _: &mut Self::Context) -> Self::Result {
+        let SetPresence { id, presence, message } = msg;
+        let unchanged = self
+            .sessions
+            .get(&id)
+            .is_some_and(|session| session.presence == presence && session.away_message == message);
+        if !unchanged {
+            self.check_flood(id, message.as_ref().map_or(0, String::len))?;
+        }
+        self.set_presence(id, presence, message);
+        Ok(())
+    }
+}
+
+impl Handler<Idle> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Idle, _: &mut Self::Context) -> Self::Result {
+        let current = match self.sessions.get(&msg.id) {
+            Some(session) => session.presence,
+            None => return,
+        };
+        match (current, msg.idle) {
+            (Presence::Online, true) => self.set_presence(msg.id, Presence::Idle, None),
+            (Presence::Idle, false) => self.set_presence(msg.id, Presence::Online, None),
+            _ => (),
+        }
+    }
+}
+
+/// Room events and room state from the other nodes of the cluster.
+///
+/// Room messages are stamped again and go into the local history as well,
+/// nodes stamp at the same time so numbers from another node would clash
+/// with our own. Room settings and bans apply on every node, kicks and
+/// mutes only on the node the target is connected to.
+impl Handler<Relayed> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Relayed, _: &mut Self::Context) -> Self::Result {
+        let (mut rooms, mut event) = match msg.0.update {
+            Update::Event { rooms, event } => (rooms, event),
+            Update::Room { room, private, password } => {
+                return self.configure_from_cluster(room, private, password)
+            }
+            Update::Ban { room, bans } => return self.ban_from_cluster(room, bans),
+            Update::Unban { room, target } => {
+                self.bans.remove(&room, &target);
+                return;
+            }
+            Update::Sync => return self.sync_cluster(),
+        };
+        // A ban may still be on its way to the node the sender is on
+        let from = match event {
+            Event::Message { ref from, .. } => from.clone(),
+            Event::File { ref from, .. } => Some(from.clone()),
+            _ => None,
+        };
+        rooms.retain(|room| !self.bans.is_banned(room, from.as_deref(), None));
+        if event.stamp().is_some() {
+            for room in &rooms {
+                let stamp = self.next_stamp(room);
+                if let Some(relayed) = event.stamp_mut() {
+                    // When it was sent still holds
+                    *relayed = Stamp { time: relayed.time, ..stamp };
+                }
+                self.history.append(room, &event);
+            }
+        }
+        let mut members: HashSet<usize> = HashSet::new();
+        for room in &rooms {
+            if let Some(room) = self.rooms.get(room) {
+                members.extend(room.members.iter());
+            }
+        }
+        for id in members {
+            self.send_to(id, event.clone());
+        }
+    }
+}
+
+impl Handler<Subscribed> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, _: Subscribed, _: &mut Self::Context) -> Self::Result {
+        // Whatever the other nodes said before is lost, ask them again
+        self.share(Update::Sync);
+    }
+}
+
+impl Handler<GetStats> for ChatServer {
+    type Result = Result<Stats, String>;
+
+    fn handle(&mut self, msg: GetStats, _: &mut Self::Context) -> Self::Result {
+        if let Some(id) = msg.id {
+            if !self.is_admin(id) {
+                return Err(String::from("only server admins can see stats"));
+            }
+        }
+        Ok(self.stats())
+    }
+}
+
+impl Handler<GetMetrics> for ChatServer {
+    type Result = String;
+
+    fn handle(&mut self, _: GetMetrics, _: &mut Self::Context) -> Self::Result {
+        let stats = self.stats();
+        let mut listed: Vec<(String, usize)> = Vec::new();
+        let mut unlisted_members = 0;
+        for (name, room) in &self.rooms {
+            if room.persistent && !room.private {
+                listed.push((name.clone(), room.members.len()));
+            } else {
+                unlisted_members += room.members.len();
+            }
+        }
+        listed.sort();
+        let snapshot = Snapshot {
+            sessions: stats.sessions,
+            detached: self.sessions.values().filter(|s| s.detached.is_some()).count(),
+            rooms: self.rooms.len(),
+            listed,
+            unlisted_members,
+            queued: stats.queued,
+            dropped: stats.dropped,
+            slow_disconnects: stats.slow_disconnects,
+        };
+        self.metrics.render(&snapshot)
+    }
+}
+
+impl Handler<HeartbeatTimeout> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, _: HeartbeatTimeout, _: &mut Self::Context) -> Self::Result {
+        self.metrics.heartbeat_timeouts += 1;
+    }
+}
+
+impl Handler<UploadTicket> for ChatServer {
+    type Result = Result<String, String>;
+
+    fn handle(&mut self, msg: UploadTicket, _: &mut Self::Context) -> Self::Result {
+        if !self.is_member(msg.id, &msg.room) {
+            return Err(format!("you are not in {}", msg.room));
+        }
+
+        let now = Instant::now();
+        self.uploads
+            .retain(|_, (_, _, issued)| now.duration_since(*issued) < UPLOAD_TICKET_TTL);
+        let ticket = format!("{:016x}{:016x}", self.rng.gen::<u64>(), self.rng.gen::<u64>());
+        self.uploads.insert(ticket.clone(), (msg.id, msg.room, now));
+        Ok(ticket)
+    }
+}
+
+impl Handler<RedeemUpload> for ChatServer {
+    type Result = Result<(usize, String), String>;
+
+    fn handle(&mut self, msg: RedeemUpload, _: &mut Self::Context) -> Self::Result {
+        match self.uploads.remove(&msg.ticket) {
+            Some((id, room, issued)) if issued.elapsed() < UPLOAD_TICKET_TTL => {
+                if self.is_member(id, &room) {
+                    Ok((id, room))
+                } else {
+                    Err(format!("you are not in {}", room))
+                }
+            }
+            _ => Err(String::from("invalid upload ticket")),
+        }
+    }
+}
+
+impl Handler<FileAccess> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: FileAccess, _: &mut Self::Context) -> Self::Result {
+        let id = self.tokens.get(&msg.token).copied().ok_or("unknown session")?;
+        if self.is_member(id, &msg.room) {
+            Ok(())
+        } else {
+            Err(format!("you are not in {}", msg.room))
+        }
+    }
+}
+
+impl Handler<ShareFile> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ShareFile, _: &mut Self::Context) -> Self::Result {
+        let ShareFile { id, room, name, size, url } = msg;
+        println!("{} shared {} ({} bytes) in {}", self.display_name(id), name, size, room);
+        let event = Event::File {
+            room: room.clone(),
+            from: self.display_name(id),
+            name,
+            size,
+            url,
+            stamp: self.stamp(&room, id),
+        };
+        self.history.append(&room, &event);
+        self.send_message(&room, event, None);
+    }
+}
+
+impl Handler<Shutdown> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Shutdown, _: &mut Self::Context) -> Self::Result {
+        println!("Shutting down: {}", msg.message);
+        self.shutting_down = true;
+        // Nobody gets to resume across a restart
+        let detached: Vec<usize> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.addr.is_none())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in detached {
+            self.remove_session(id);
+        }
+        for session in self.sessions.values_mut() {
+            session.flush();
+            if let Some(ref addr) = session.addr {
+                // Skips a full mailbox, everyone has to hear about this
+                let event = Event::Shutdown {
+                    message: msg.message.clone(),
+                };
+                let _ = addr.do_send(Message(event));
+            }
+        }
+    }
+}
+
+impl Handler<Flush> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, _: Flush, _: &mut Self::Context) -> Self::Result {
+        self.bans.save();
+        self.history.sync();
+    }
+}
+
+impl Handler<AdminRooms> for ChatServer {
+    type Result = MessageResult<AdminRooms>;
+
+    fn handle(&mut self, _: AdminRooms, _: &mut Self::Context) -> Self::Result {
+        let mut rooms: Vec<RoomInfo> = self
+            .rooms
+            .iter()
+            .map(|(name, room)| RoomInfo {
+                name: name.clone(),
+                members: room.members.len(),
+                private: room.private,
+                persistent: room.persistent,
+            })
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        MessageResult(rooms)
+    }
+}
+
+impl Handler<AdminMembers> for ChatServer {
+    type Result = Result<Vec<SessionInfo>, String>;
+
+    fn handle(&mut self, msg: AdminMembers, _: &mut Self::Context) -> Self::Result {
+        let room = self
+            .rooms
+            .get(&msg.room)
+            .ok_or_else(|| format!("no such room: {}", msg.room))?;
+        let mut members: Vec<SessionInfo> = room
+            .members
+            .iter()
+            .filter_map(|id| self.sessions.get(id).map(|session| (*id, session)))
+            .map(|(id, session)| SessionInfo {
+                id,
+                name: session.name.clone(),
+                ip: session.ip.clone(),
+                presence: session.presence,
+                detached: session.detached.is_some(),
+                op: room.ops.contains(&id),
+            })
+            .collect();
+        members.sort_by_key(|member| member.id);
+        Ok(members)
+    }
+}
+
+impl Handler<SystemBroadcast> for ChatServer {
+    type Result = Result<Stamp, String>;
+
+    fn handle(&mut self, msg: SystemBroadcast, _: &mut Self::Context) -> Self::Result {
+        let SystemBroadcast { room, text } = msg;
+        if !self.rooms.contains_key(&room) {
+            return Err(format!("no such room: {}", room));
+        }
+        // Nobody sent it, so nobody gets receipts for it
+        let stamp = self.next_stamp(&room);
+        let event = Event::Message {
+            room: room.clone(),
+            from: None,
+            text,
+            stamp,
+        };
+        self.history.append(&room, &event);
+        self.send_message(&room, event, None);
+        Ok(stamp)
+    }
+}
+
+impl Handler<ForceDisconnect> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: ForceDisconnect, _: &mut Self::Context) -> Self::Result {
+        let session = self
+            .sessions
+            .get(&msg.id)
+            .ok_or_else(|| format!("no such session: {}", msg.id))?;
+        if let Some(ref addr) = session.addr {
+            // Skips a full mailbox, the session has to learn it is closed
+            let _ = addr.do_send(Message(Event::Disconnected { reason: msg.reason }));
+        }
+        self.remove_session(msg.id);
+        Ok(())
+    }
+}
+
+impl Handler<CloseRoom> for ChatServer {
+    type Result = Result<(), CloseRefused>;
+
+    fn handle(&mut self, msg: CloseRoom, _: &mut Self::Context) -> Self::Result {
+        let CloseRoom { room } = msg;
+        let members: Vec<usize> = match self.rooms.get(&room) {
+            Some(r) if r.persistent => return Err(CloseRefused::Permanent(room)),
+            Some(r) => r.members.iter().copied().collect(),
+            None => return Err(CloseRefused::NoSuchRoom(room)),
+        };
+        println!("Closing room {}", room);
+        for id in members {
+            self.remove_from_room(id, &room);
+        }
+        self.rooms.remove(&room);
+        self.history.delete(&room);
+        Ok(())
+    }
+}
+
+impl ChatServer {
+    /// A session id that no current session has. Ids count up from 1, so
+    /// the only way to meet one in use is after wrapping around.
+    fn next_session_id(&mut self) -> usize {
+        loop {
+            let id = self.next_id;
+            self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+            if !self.sessions.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    /// Attach a new connection to a session, sending it what it missed
+    fn resume(&mut self, id: usize, conn: u64, addr: Recipient<Message>) -> Connected {
+        let rooms: Vec<String> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.members.contains(&id))
+            .map(|(name, _)| name.clone())
+            .collect();
+        let session = self.sessions.get_mut(&id).expect("tokens only point at sessions");
+        // The old connection may not have noticed it is dead yet
+        if let Some(old) = session.addr.take() {
+            let reason = String::from("session resumed on another connection");
+            let _ = old.do_send(Message(Event::Disconnected { reason }));
+        }
+        session.addr = Some(addr);
+        session.conn = conn;
+        session.detached = None;
+        session.flush();
+        println!("{} resumed", session.name.as_deref().unwrap_or("Someone"));
+
+        Connected {
+            id,
+            conn,
+            token: session.token.clone(),
+            resumed: true,
+            name: session.name.clone(),
+            rooms,
+            active: session.active.take(),
+        }
+    }
+
+    /// Forget a session for good, letting its rooms know it is gone
+    fn remove_session(&mut self, id: usize) {
+        let name = self.display_name(id);
+        println!("{} disconnected", name);
+
+        let mut rooms: Vec<String> = Vec::new();
+
+        let typing: Vec<String> = self
+            .sessions
+            .get(&id)
+            .map(|session| session.typing.keys().cloned().collect())
+            .unwrap_or_default();
+        for room in typing {
+            self.stop_typing(id, &room);
+        }
+
+        // Removing the session also releases its nickname
+        if let Some(session) = self.sessions.remove(&id) {
+            self.tokens.remove(&session.token);
+            self.stats.dropped += session.outbox.dropped;
+            for (name, room) in &mut self.rooms {
+                if room.forget(id) {
+                    rooms.push(String::from(name));
+                }
+            }
+        }
+
+        for room in rooms {
+            let event = Event::Disconnect {
+                room: room.clone(),
+                name: name.clone(),
+            };
+            self.send_message(&room, event, None);
+        }
+    }
+
+    /// Push queued events on to sessions that fell behind, disconnecting
+    /// the ones the slow consumer policy gave up on
+    fn flush_outboxes(&mut self) {
+        let mut slow: Vec<usize> = Vec::new();
+        for (id, session) in &mut self.sessions {
+            session.flush();
+            if session.outbox.overflowed {
+                slow.push(*id);
+            }
+        }
+
+        for id in slow {
+            println!("{} is not keeping up, disconnecting", self.display_name(id));
+            if let Some(addr) = self.sessions.get(&id).and_then(|session| session.addr.clone()) {
+                // Skips the full mailbox, so the session learns why it is closed
+                let reason = String::from("too slow to keep up");
+                let _ = addr.do_send(Message(Event::Disconnected { reason }));
+            }
+            self.stats.slow_disconnects += 1;
+            self.remove_session(id);
+        }
+    }
+
+    /// Give up on dropped sessions that were not resumed in time
+    fn sweep_detached(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<usize> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| {
+                session
+                    .detached
+                    .is_some_and(|since| now.duration_since(since) >= self.resume_grace)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.remove_session(id);
+        }
+    }
+
+    /// Stamp the next message of the room and remember who sent it
+    fn stamp(&mut self, room: &str, sender: usize) -> Stamp {
+        let stamp = self.next_stamp(room);
+        if let Some(room) = self.rooms.get_mut(room) {
+            room.record(Sent {
+                seq: stamp.seq,
+                id: stamp.id,
+                sender,
+            });
+        }
+        stamp
+    }
+
+    /// Stamp the next message of the room
+    fn next_stamp(&mut self, room: &str) -> Stamp {
+        let stamp = Stamp {
+            id: self.next_message_id,
+            seq: self.history.last_seq(room) + 1,
+            time: unix_millis(),
+        };
+        self.next_message_id += 1;
+        stamp
+    }
+
+    fn set_presence(&mut self, id: usize, presence: Presence, message: Option<String>) {
+        let session = match self.sessions.get_mut(&id) {
+            Some(session) => session,
+            None => return,
+        };
+        if session.presence == presence && session.away_message == message {
+            return;
+        }
+        session.presence = presence;
+        session.away_message = message.clone();
+
+        let event = Event::Presence {
+            name: self.display_name(id),
+            presence,
+            message,
+        };
+        self.send_to_peers(id, event, true);
+    }
+
+    fn stop_typing(&mut self, id: usize, room: &str) {
+        let stopped = self
+            .sessions
+            .get_mut(&id)
+            .is_some_and(|session| session.typing.remove(room).is_some());
+        if stopped {
+            let event = Event::Typing {
+                room: room.to_owned(),
+                name: self.display_name(id),
+                typing: false,
+            };
+            self.send_message(room, event, Some(id));
+        }
+    }
+
+    /// Stop typing indicators that were not renewed in time
+    fn sweep_typing(&mut self) {
+        let now = Instant::now();
+        let mut expired: Vec<(usize, String)> = Vec::new();
+        for (id, session) in &self.sessions {
+            for (room, since) in &session.typing {
+                if now.duration_since(*since) >= TYPING_TIMEOUT {
+                    expired.push((*id, room.clone()));
+                }
+            }
+        }
+        for (id, room) in expired {
+            self.stop_typing(id, &room);
+        }
+    }
+
+    /// Send an event once to everyone who shares a room with the session
+    fn send_to_peers(&mut self, id: usize, event: Event, include_self: bool) {
+        let mut rooms: Vec<String> = Vec::new();
+        let mut peers: HashSet<usize> = HashSet::new();
+        for (name, room) in &self.rooms {
+            if room.members.contains(&id) {
+                rooms.push(name.clone());
+                peers.extend(room.members.iter());
+            }
+        }
+        self.publish(rooms, &event);
+        if !include_self {
+            peers.remove(&id);
+        }
+        for peer in peers {
+            self.send_to(peer, event.clone());
+        }
+    }
+
+    /// Run a message, or anything else the rooms of the sender hear about,
+    /// through the flood protection of its sender
+    fn check_flood(&mut self, id: usize, len: usize) -> Result<(), String> {
+        let verdict = match self.sessions.get_mut(&id) {
+            Some(session) => session.limiter.check(len),
+            None => return Err(String::from("not connected")),
+        };
+        match verdict {
+            Verdict::Allow => Ok(()),
+            Verdict::TooLong(limit) => Err(format!("that is too long, the limit is {} bytes", limit)),
+            Verdict::Warn => Err(String::from("slow down, you are sending too fast")),
+            Verdict::Mute(duration) => {
+                println!("{} muted for flooding", self.display_name(id));
+                Err(format!("you are muted for {}s for flooding", duration.as_secs()))
+            }
+            Verdict::Muted(left) => Err(format!(
+                "you are muted for another {}s for flooding",
+                left.as_secs() + 1
+            )),
+            Verdict::Disconnect => {
+                println!("{} disconnected for flooding", self.display_name(id));
+                let reason = String::from("flooding");
+                self.send_to(id, Event::Disconnected { reason: reason.clone() });
+                Err(reason)
+            }
+        }
+    }
+
+    fn name(&self, id: usize) -> Option<&str> {
+        self.sessions.get(&id).and_then(|s| s.name.as_deref())
+    }
+
+    /// Admin rights go with the name a session logged in as, never with
+    /// one it picked for itself
+    fn is_admin(&self, id: usize) -> bool {
+        self.sessions.get(&id).is_some_and(|session| {
+            session.verified && session.name.as_ref().is_some_and(|name| self.admins.contains(name))
+        })
+    }
+
+    /// Admins operate every room, everybody else needs to be an op of the room
+    fn is_op(&self, id: usize, room: &str) -> bool {
+        self.is_admin(id) || self.rooms.get(room).is_some_and(|r| r.ops.contains(&id))
+    }
+
+    /// Take a session out of a room against its will, and tell it so
+    fn remove_from_room(&mut self, id: usize, room: &str) {
+        self.stop_typing(id, room);
+        if let Some(r) = self.rooms.get_mut(room) {
+            if r.leave(id) {
+                self.send_to(id, Event::Left { room: room.to_owned() });
+            }
+        }
+    }
+
+    fn is_member(&self, id: usize, room: &str) -> bool {
+        self.rooms
+            .get(room)
+            .is_some_and(|room| room.members.contains(&id))
+    }
+
+    fn find_session(&self, name: &str) -> Option<usize> {
+        self.sessions
+            .iter()
+            .find(|(_, s)| s.name.as_deref().is_some_and(|n| same_name(n, name)))
+            .map(|(id, _)| *id)
+    }
+
+    /// Name used in notices, falls back to "Someone" for unnamed sessions
+    fn display_name(&self, id: usize) -> String {
+        self.name(id)
+            .map(str::to_owned)
+            .unwrap_or_else(|| String::from("Someone"))
+    }
+
+    /// Remove rooms that have been empty for longer than `ROOM_GRACE`
+    fn sweep_rooms(&mut self) {
+        let now = Instant::now();
+        let mut expired: Vec<String> = Vec::new();
+        for (name, room) in &mut self.rooms {
+            if !room.members.is_empty() || room.persistent {
+                room.empty_since = None;
+                continue;
+            }
+            let since = *room.empty_since.get_or_insert(now);
+            if now.duration_since(since) >= ROOM_GRACE {
+                expired.push(name.clone());
+            }
+        }
+
+        for room in expired {
+            println!("Removing empty room {}", room);
+            self.remove_room(&room);
+        }
+    }
+
+    /// Remove a room. The log of a private or password room goes with it,
+    /// so whoever creates a room of the same name later can not read it.
+    fn remove_room(&mut self, name: &str) {
+        match self.rooms.remove(name) {
+            Some(room) if room.private || room.password.is_some() => self.history.delete(name),
+            _ => self.history.forget(name),
+        }
+    }
+
+    fn send_to(&mut self, id: usize, event: Event) {
+        if let Some(session) = self.sessions.get_mut(&id) {
+            session.send(event, &self.backpressure);
+        }
+    }
+
+    /// Pass an event on to the other nodes, if there are any
+    fn publish(&self, rooms: Vec<String>, event: &Event) {
+        self.share(Update::Event {
+            rooms,
+            event: event.clone(),
+        });
+    }
+
+    /// Pass an update on to the other nodes, if there are any
+    fn share(&self, update: Update) {
+        if let Some(ref publisher) = self.publisher {
+            publisher.do_send(Publish(Relay {
+                node: self.node.clone(),
+                update,
+            }));
+        }
+    }
+
+    /// Tell the other nodes who may join the room
+    fn share_room(&mut self, name: &str) {
+        let (private, password) = match self.rooms.get(name) {
+            Some(room) if self.publisher.is_some() => (room.private, room.password.clone()),
+            _ => return,
+        };
+        self.remember_room(name, private, password.clone());
+        self.share(Update::Room {
+            room: name.to_owned(),
+            private,
+            password,
+        });
+    }
+
+    fn remember_room(&mut self, name: &str, private: bool, password: Option<String>) {
+        if private || password.is_some() {
+            self.cluster_rooms.insert(name.to_owned(), (private, password));
+        } else {
+            self.cluster_rooms.remove(name);
+        }
+    }
+
+    /// Another node changed who may join a room
+    fn configure_from_cluster(&mut self, name: String, private: bool, password: Option<String>) {
+        self.remember_room(&name, private, password.clone());
+        let r = match self.rooms.get_mut(&name) {
+            Some(r) if r.private != private || r.password != password => r,
+            _ => return,
+        };
+        r.private = private;
+        r.password = password;
+        let event = Event::RoomSettings {
+            room: name.clone(),
+            private,
+            password: r.password.is_some(),
+        };
+        let members: Vec<usize> = r.members.iter().copied().collect();
+        for id in members {
+            self.send_to(id, event.clone());
+        }
+    }
+
+    /// Another node banned someone from a room, they go here as well
+    fn ban_from_cluster(&mut self, room: String, bans: Vec<Ban>) {
+        for ban in bans {
+            self.bans.add(&room, ban);
+        }
+        let members = match self.rooms.get(&room) {
+            Some(r) => r.members.clone(),
+            None => return,
+        };
+        for id in members {
+            let banned = self.sessions.get(&id).is_some_and(|session| {
+                self.bans
+                    .is_banned(&room, session.name.as_deref(), session.ip.as_deref())
+            });
+            if banned {
+                self.remove_from_room(id, &room);
+            }
+        }
+    }
+
+    /// Tell a node that just came up how rooms are set up
+    fn sync_cluster(&self) {
+        for (room, (private, password)) in &self.cluster_rooms {
+            self.share(Update::Room {
+                room: room.clone(),
+                private: *private,
+                password: password.clone(),
+            });
+        }
+        for (room, bans) in self.bans.rooms() {
+            self.share(Update::Ban {
+                room: room.clone(),
+                bans: bans.clone(),
+            });
+        }
+    }
+
+    /// Send an event to every member of the room, except `skip` if given,
+    /// here and on the other nodes
+    fn send_message(&mut self, room: &str, event: Event, skip: Option<usize>) {
+        self.publish(vec![room.to_owned()], &event);
+        let start = Instant::now();
+        if let Some(room) = self.rooms.get(room) {
+            for session_id in &room.members {
+                if Some(*session_id) != skip {
+                    if let Some(session) = self.sessions.get_mut(session_id) {
+                        session.send(event.clone(), &self.backpressure);
+                    }
+                }
+            }
+        }
+        self.metrics.fanout.observe(start.elapsed());
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = self.stats.clone();
+        stats.sessions = self.sessions.len();
+        stats.rooms = self.rooms.len();
+        stats.queued = self.sessions.values().map(|session| session.outbox.len()).sum();
+        stats.dropped += self
+            .sessions
+            .values()
+            .map(|session| session.outbox.dropped)
+            .sum::<u64>();
+        stats
+    }
+
+    /// Send the recent history of the room to a single session
+    fn replay_history(&mut self, room: &str, id: usize) {
+        if let Some(session) = self.sessions.get_mut(&id) {
+            for event in self.history.replay(room) {
+                session.send(event, &self.backpressure);
+            }
+        }
+    }
+}
+
+/// Whether two nicknames belong to the same user, which does not depend on
+/// ASCII case
+pub fn same_name(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::HISTORY_REPLAY;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Stands in for a WebSocket session, keeping what it is sent
+    struct Collector(Arc<Mutex<Vec<Event>>>);
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Message> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, msg: Message, _: &mut Self::Context) {
+            self.0.lock().unwrap().push(msg.0);
+        }
+    }
+
+    /// A server keeping its bans and history in a directory of its own, so
+    /// tests leave the working tree alone
+    fn server() -> ChatServer {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "chat-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let bans = Bans::load(dir.join("bans.json"));
+        let history = History::new(dir.join("history"), HISTORY_REPLAY);
+        ChatServer::new(Vec::new(), Vec::new(), bans, history)
+    }
+
+    fn collector() -> Recipient<Message> {
+        Collector(Arc::new(Mutex::new(Vec::new()))).start().recipient()
+    }
+
+    /// A session without a connection, whatever it is sent piles up in
+    /// its outbox
+    fn detached() -> Session {
+        Session::new(None, 0, String::new(), &RateLimits::default())
+    }
+
+    fn connect(addr: Recipient<Message>) -> Connect {
+        Connect {
+            addr,
+            ip: None,
+            resume: None,
+            user: None,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn connections_get_distinct_ids() {
+        let server = server().start();
+        let mut ids = HashSet::new();
+        for _ in 0..1000 {
+            let connected = server.send(connect(collector())).await.unwrap();
+            assert_ne!(connected.id, 0);
+            assert!(ids.insert(connected.id), "id {} handed out twice", connected.id);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn connect_does_not_overwrite_a_session() {
+        let mut server = server();
+        server.sessions.insert(5, detached());
+        server.rooms.get_mut(MAIN_ROOM).unwrap().members.insert(5);
+        server.next_id = 5;
+        let server = server.start();
+
+        let connected = server.send(connect(collector())).await.unwrap();
+        assert_eq!(connected.id, 6);
+        let members = server
+            .send(ListMembers {
+                id: connected.id,
+                room: String::from(MAIN_ROOM),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let names: Vec<String> = members.into_iter().map(|member| member.name).collect();
+        assert_eq!(names, vec!["#5", "#6"]);
+    }
+
+    #[test]
+    fn ids_in_use_are_skipped_after_wrapping_around() {
+        let mut server = server();
+        server.sessions.insert(usize::MAX, detached());
+        server.sessions.insert(1, detached());
+        server.next_id = usize::MAX;
+        assert_eq!(server.next_session_id(), 2);
+        assert_eq!(server.next_session_id(), 3);
+    }
+
+    #[test]
+    fn send_message_skips_only_the_given_session() {
+        let mut server = server();
+        for id in 0..3 {
+            server.sessions.insert(id, detached());
+            server.rooms.get_mut(MAIN_ROOM).unwrap().members.insert(id);
+        }
+        let event = Event::Error {
+            message: String::from("hello"),
+        };
+
+        server.send_message(MAIN_ROOM, event.clone(), Some(1));
+        let missed: Vec<usize> = (0..3).map(|id| server.sessions[&id].outbox.len()).collect();
+        assert_eq!(missed, vec![1, 0, 1]);
+
+        // Id 0 is a session like any other, not "skip nobody"
+        server.send_message(MAIN_ROOM, event, None);
+        let missed: Vec<usize> = (0..3).map(|id| server.sessions[&id].outbox.len()).collect();
+        assert_eq!(missed, vec![2, 1, 2]);
+    }
+
+    #[test]
+    fn sessions_are_found_by_name_in_any_case() {
+        let mut server = server();
+        let mut session = detached();
+        session.name = Some(String::from("bob"));
+        server.sessions.insert(1, session);
+        assert_eq!(server.find_session("BOB"), Some(1));
+        assert_eq!(server.find_session("bobby"), None);
+    }
+
+    #[test]
+    fn only_a_verified_name_is_an_admin() {
+        let mut server = server();
+        server.admins.insert(String::from("root"));
+        for (id, verified) in [(1, false), (2, true)] {
+            let mut session = detached();
+            session.name = Some(String::from("root"));
+            session.verified = verified;
+            server.sessions.insert(id, session);
+        }
+        assert!(!server.is_admin(1));
+        assert!(server.is_admin(2));
+    }
+
+    #[test]
+    fn a_removed_private_room_does_not_replay_its_history() {
+        let room = String::from("incident");
+        let mut server = server();
+        server.sessions.insert(1, detached());
+        let mut private = Room {
+            private: true,
+            ..Room::default()
+        };
+        private.members.insert(1);
+        server.rooms.insert(room.clone(), private);
+        let event = Event::Message {
+            room: room.clone(),
+            from: None,
+            text: String::from("the root password is hunter2"),
+            stamp: server.next_stamp(&room),
+        };
+        server.history.append(&room, &event);
+
+        let r = server.rooms.get_mut(&room).unwrap();
+        r.leave(1);
+        r.empty_since = Some(Instant::now() - ROOM_GRACE);
+        server.sweep_rooms();
+        assert!(!server.rooms.contains_key(&room));
+
+        // Somebody else creates a room by the same name
+        server.sessions.insert(2, detached());
+        server.rooms.insert(room.clone(), Room::default());
+        server.rooms.get_mut(&room).unwrap().members.insert(2);
+        server.replay_history(&room, 2);
+        assert_eq!(server.sessions[&2].outbox.len(), 0);
+        assert_eq!(server.history.last_seq(&room), 0);
+    }
+
+    #[test]
+    fn typing_stops_when_a_session_is_taken_out_of_the_room() {
+        let mut server = server();
+        for id in 1..=2 {
+            server.sessions.insert(id, detached());
+            server.rooms.get_mut(MAIN_ROOM).unwrap().members.insert(id);
+        }
+        server
+            .sessions
+            .get_mut(&1)
+            .unwrap()
+            .typing
+            .insert(String::from(MAIN_ROOM), Instant::now());
+
+        server.remove_from_room(1, MAIN_ROOM);
+        assert!(server.sessions[&1].typing.is_empty());
+        // The typing notice going away
+        assert_eq!(server.sessions[&2].outbox.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn a_session_banned_from_the_main_room_is_kept_out_of_it() {
+        let mut server = server();
+        let ban = Ban {
+            name: None,
+            ip: Some(String::from("10.0.0.2")),
+        };
+        server.bans.add(MAIN_ROOM, ban);
+        let server = server.start();
+
+        let connected = server
+            .send(Connect {
+                ip: Some(String::from("10.0.0.2")),
+                ..connect(collector())
+            })
+            .await
+            .unwrap();
+        assert!(connected.rooms.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn a_mute_outlasts_a_reconnect() {
+        let mut server = server();
+        server.admins.insert(String::from("root"));
+        let server = server.start();
+        let root = Connect {
+            user: Some(String::from("root")),
+            ..connect(collector())
+        };
+        let root = server.send(root).await.unwrap();
+        let bob = Connect {
+            user: Some(String::from("bob")),
+            ..connect(collector())
+        };
+        let bob = server.send(bob).await.unwrap();
+        let mute = Moderate {
+            id: root.id,
+            room: String::from(MAIN_ROOM),
+            target: String::from("bob"),
+            action: Action::Mute(Duration::from_secs(3600)),
+        };
+        server.send(mute).await.unwrap().unwrap();
+        let disconnect = Disconnect {
+            id: bob.id,
+            conn: bob.conn,
+            active: None,
+        };
+        server.send(disconnect).await.unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let bob = Connect {
+            user: Some(String::from("bob")),
+            ..connect(Collector(events.clone()).start().recipient())
+        };
+        let id = server.send(bob).await.unwrap().id;
+        let msg = String::from("I'm back");
+        server
+            .send(ClientMessage { id, msg, room: String::from(MAIN_ROOM) })
+            .await
+            .unwrap();
+        actix_rt::time::delay_for(Duration::from_millis(50)).await;
+
+        let refused = events.lock().unwrap().iter().any(|event| match event {
+            Event::Error { message } => message.starts_with("you are muted in Main"),
+            _ => false,
+        });
+        assert!(refused);
+    }
+
+    #[actix_rt::test]
+    async fn relayed_messages_are_numbered_after_our_own() {
+        let room = String::from(MAIN_ROOM);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let server = server().start();
+        let addr = Collector(events.clone()).start().recipient();
+        let id = server.send(connect(addr)).await.unwrap().id;
+        let msg = String::from("here");
+        server.do_send(ClientMessage { id, msg, room: room.clone() });
+        // Another node stamped its message at the same time
+        let relay = Relay {
+            node: String::from("elsewhere"),
+            update: Update::Event {
+                rooms: vec![room.clone()],
+                event: Event::Message {
+                    room,
+                    from: None,
+                    text: String::from("there"),
+                    stamp: Stamp { id: 1, seq: 1, time: 0 },
+                },
+            },
+        };
+        server.do_send(Relayed(relay.clone()));
+        server.send(Relayed(relay)).await.unwrap();
+        actix_rt::time::delay_for(Duration::from_millis(50)).await;
+
+        let seqs: Vec<u64> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| event.stamp().map(|stamp| stamp.seq))
+            .collect();
+        // Our own message is 1, the sender does not get it back
+        assert_eq!(seqs, vec![2, 3]);
+    }
+
+    #[actix_rt::test]
+    async fn a_room_made_private_on_another_node_is_private_here() {
+        let server = server().start();
+        let relay = Relay {
+            node: String::from("elsewhere"),
+            update: Update::Room {
+                room: String::from("incident"),
+                private: true,
+                password: None,
+            },
+        };
+        server.send(Relayed(relay)).await.unwrap();
+
+        let id = server.send(connect(collector())).await.unwrap().id;
+        let join = Join {
+            id,
+            room: String::from("incident"),
+            password: None,
+        };
+        let refused = server.send(join).await.unwrap().unwrap_err();
+        assert_eq!(refused, "can not join incident: room is invite only");
     }
 }
@@ -0,0 +1,527 @@
+use actix::dev::ToEnvelope;
+use actix::prelude::*;
+use actix_web::{Error, HttpRequest};
+use actix_web_actors::ws::CloseCode;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::auth::Users;
+use crate::protocol::{self, query_param, Command, Event, Format};
+use crate::server;
+
+/// Without any message from the user for this long the session is idle
+const IDLE_AFTER: Duration = Duration::from_secs(300);
+
+/// What a connection knows about its chat session, whatever carries it
+pub struct ChatSession {
+    pub id: usize,
+    /// Connection number the server gave us, see `server::Connected`
+    pub conn: u64,
+    /// Token of the session to resume
+    pub resume: Option<String>,
+    /// The session ends for good when the connection does, rather than
+    /// being held for a resume
+    pub leaving: bool,
+    /// Last message from the user, heartbeats do not count
+    pub active: Instant,
+    pub idle: bool,
+    /// Room that plain messages go to
+    pub room: String,
+    /// Every room the session is in, in the order they were joined
+    pub rooms: Vec<String>,
+    pub name: Option<String>,
+    /// Who the connection authenticated as, if users are configured
+    pub user: Option<String>,
+    pub format: Format,
+    pub ip: Option<String>,
+    pub addr: Addr<server::ChatServer>,
+}
+
+impl ChatSession {
+    pub fn new(
+        addr: Addr<server::ChatServer>,
+        format: Format,
+        ip: Option<String>,
+        resume: Option<String>,
+        user: Option<String>,
+    ) -> ChatSession {
+        ChatSession {
+            id: 0,
+            conn: 0,
+            resume,
+            leaving: false,
+            active: Instant::now(),
+            idle: false,
+            room: String::from(server::MAIN_ROOM),
+            rooms: vec![String::from(server::MAIN_ROOM)],
+            name: None,
+            user,
+            format,
+            ip,
+            addr,
+        }
+    }
+
+    /// A session for the client making the request, turning away the
+    /// ones that do not authenticate
+    pub fn from_request(
+        req: &HttpRequest,
+        users: &Users,
+        addr: Addr<server::ChatServer>,
+    ) -> Result<ChatSession, Error> {
+        let user = users.authenticate(req)?;
+        let format = Format::from_query(req.query_string());
+        let ip = req.peer_addr().map(|addr| addr.ip().to_string());
+        let resume = query_param(req.query_string(), "resume").map(str::to_owned);
+        Ok(ChatSession::new(addr, format, ip, resume, user))
+    }
+
+    /// Tell the server when the user goes idle or comes back
+    pub fn check_idle(&mut self) {
+        let idle = Instant::now().duration_since(self.active) > IDLE_AFTER;
+        if idle != self.idle {
+            self.idle = idle;
+            self.addr.do_send(server::Idle { id: self.id, idle });
+        }
+    }
+}
+
+/// A line of input for a session, as a WebSocket client would send it
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Input(pub String);
+
+/// Sessions of the HTTP transports by their token, so the requests carrying
+/// their input can find them
+pub struct Registry<T>(Mutex<HashMap<String, (u64, T)>>);
+
+pub type Inputs = Registry<Recipient<Input>>;
+
+impl<T> Default for Registry<T> {
+    fn default() -> Registry<T> {
+        Registry(Mutex::new(HashMap::new()))
+    }
+}
+
+impl<T: Clone> Registry<T> {
+    pub fn insert(&self, token: String, conn: u64, session: T) {
+        self.0.lock().unwrap().insert(token, (conn, session));
+    }
+
+    /// Forget a connection, unless the session moved on to another one
+    pub fn remove(&self, token: &str, conn: u64) {
+        let mut sessions = self.0.lock().unwrap();
+        if sessions.get(token).is_some_and(|(c, _)| *c == conn) {
+            sessions.remove(token);
+        }
+    }
+
+    pub fn get(&self, token: &str) -> Option<T> {
+        self.0.lock().unwrap().get(token).map(|(_, session)| session.clone())
+    }
+}
+
+/// A connection carrying a chat session: a WebSocket, an event stream or
+/// a series of polls. The chat itself works the same on all of them.
+pub trait Transport: Actor + Handler<server::Message> {
+    fn session(&mut self) -> &mut ChatSession;
+
+    /// Hand an event to the client
+    fn send_event(&mut self, event: Event, ctx: &mut Self::Context);
+
+    /// End the connection, the server is done with the session. `code` is
+    /// for transports that can send one.
+    fn close(&mut self, code: CloseCode, reason: String, ctx: &mut Self::Context);
+
+    /// The server gave us a session
+    fn attached(&mut self, _token: &str, _ctx: &mut Self::Context) {}
+
+    /// Register with the chat server
+    fn connect(&mut self, ctx: &mut Self::Context)
+    where
+        Self::Context: AsyncContext<Self> + ToEnvelope<Self, server::Message>,
+    {
+        let session = self.session();
+        let msg = server::Connect {
+            addr: ctx.address().recipient(),
+            ip: session.ip.clone(),
+            resume: session.resume.clone(),
+            user: session.user.clone(),
+        };
+        session
+            .addr
+            .send(msg)
+            .into_actor(self) // Converts the future into ActorFuture
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(connected) => act.connected(connected, ctx),
+                    _ => ctx.stop(),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Take over the session the server gave us, a resumed one comes with
+    /// its name and rooms
+    fn connected(&mut self, connected: server::Connected, ctx: &mut Self::Context) {
+        if self.session().resume.take().is_some() && !connected.resumed {
+            let message = String::from("the session expired, starting a new one");
+            self.send_event(Event::Error { message }, ctx);
+        }
+        let session = self.session();
+        session.id = connected.id;
+        session.conn = connected.conn;
+        session.name = connected.name;
+        // A new session may be kept out of the main room by a ban
+        session.rooms = connected.rooms;
+        session.room = match connected.active {
+            Some(room) if session.rooms.contains(&room) => room,
+            _ => session.rooms.last().cloned().unwrap_or_default(),
+        };
+        self.attached(&connected.token, ctx);
+        let event = Event::Session {
+            token: connected.token,
+            resumed: connected.resumed,
+        };
+        self.send_event(event, ctx);
+    }
+
+    /// Let the server know the connection is gone
+    fn disconnect(&mut self) {
+        let session = self.session();
+        let active = if session.leaving {
+            None
+        } else {
+            Some(session.room.clone())
+        };
+        session.addr.do_send(server::Disconnect {
+            id: session.id,
+            conn: session.conn,
+            active,
+        });
+    }
+
+    /// Pass on an event from the chat server
+    fn handle_event(&mut self, event: Event, ctx: &mut Self::Context) {
+        match event {
+            Event::Left { ref room } => {
+                // We were kicked out of a room
+                let room = room.clone();
+                self.send_event(event, ctx);
+                self.left(&room, ctx);
+            }
+            Event::Disconnected { ref reason } => {
+                let reason = reason.clone();
+                self.send_event(event, ctx);
+                self.session().leaving = true;
+                self.close(CloseCode::Policy, reason, ctx);
+            }
+            Event::Shutdown { ref message } => {
+                let message = message.clone();
+                self.send_event(event, ctx);
+                self.session().leaving = true;
+                self.close(CloseCode::Away, message, ctx);
+            }
+            _ => self.send_event(event, ctx),
+        }
+    }
+
+    /// Act on a line the user sent
+    fn handle_input(&mut self, text: &str, ctx: &mut Self::Context)
+    where
+        Self::Context: AsyncContext<Self>,
+    {
+        self.session().active = Instant::now();
+        let cmd = match self.session().format {
+            Format::Text => Command::parse(text),
+            Format::Json => serde_json::from_str(text).map_err(|e| format!("invalid command: {}", e)),
+        };
+        match cmd {
+            Ok(cmd) => self.handle_command(cmd, ctx),
+            Err(message) => self.send_event(Event::Error { message }, ctx),
+        }
+    }
+
+    /// Forget a room we are no longer in
+    fn left(&mut self, room: &str, ctx: &mut Self::Context) {
+        let session = self.session();
+        session.rooms.retain(|r| r != room);
+        if session.room == room {
+            // Fall back to the room we joined most recently
+            if let Some(last) = session.rooms.last() {
+                session.room = last.clone();
+                let room = session.room.clone();
+                self.send_event(Event::Active { room }, ctx);
+            }
+        }
+    }
+
+    fn moderate(
+        &mut self,
+        target: String,
+        room: Option<String>,
+        action: server::Action,
+        ctx: &mut Self::Context,
+    ) where
+        Self::Context: AsyncContext<Self>,
+    {
+        let session = self.session();
+        let msg = server::Moderate {
+            id: session.id,
+            room: room.unwrap_or_else(|| session.room.clone()),
+            target,
+            action,
+        };
+        self.request(msg, ctx);
+    }
+
+    /// Send a request to the chat server, only reporting back when it fails
+    fn request<M>(&mut self, msg: M, ctx: &mut Self::Context)
+    where
+        Self::Context: AsyncContext<Self>,
+        M: actix::Message<Result = Result<(), String>> + Send + 'static,
+        server::ChatServer: Handler<M>,
+    {
+        self.session()
+            .addr
+            .send(msg)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => (),
+                    Ok(Err(message)) => act.send_event(Event::Error { message }, ctx),
+                    _ => println!("Something is wrong"),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+
+    fn handle_command(&mut self, cmd: Command, ctx: &mut Self::Context)
+    where
+        Self::Context: AsyncContext<Self>,
+    {
+        let id = self.session().id;
+        let current = self.session().room.clone();
+        match cmd {
+            Command::List => {
+                // Send ListRooms message to chat server and wait for
+                // response
+                println!("List rooms");
+                self.session()
+                    .addr
+                    .send(server::ListRooms)
+                    .into_actor(self)
+                    .then(|res, act, ctx| {
+                        match res {
+                            Ok(rooms) => act.send_event(Event::Rooms { rooms }, ctx),
+                            _ => println!("Something is wrong"),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+                // .wait(ctx) pauses all events in context,
+                // so actor wont receive any new messages until it get list
+                // of rooms back
+            }
+            Command::Stats => {
+                self.session()
+                    .addr
+                    .send(server::GetStats { id: Some(id) })
+                    .into_actor(self)
+                    .then(|res, act, ctx| {
+                        match res {
+                            Ok(Ok(stats)) => {
+                                let event = Event::Stats {
+                                    sessions: stats.sessions,
+                                    rooms: stats.rooms,
+                                    queued: stats.queued,
+                                    dropped: stats.dropped,
+                                    slow_disconnects: stats.slow_disconnects,
+                                };
+                                act.send_event(event, ctx)
+                            }
+                            Ok(Err(message)) => act.send_event(Event::Error { message }, ctx),
+                            _ => println!("Something is wrong"),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+            }
+            Command::Who { room } => {
+                let room = room.unwrap_or(current);
+                self.session()
+                    .addr
+                    .send(server::ListMembers {
+                        id,
+                        room: room.clone(),
+                    })
+                    .into_actor(self)
+                    .then(|res, act, ctx| {
+                        match res {
+                            Ok(Ok(members)) => act.send_event(Event::Members { room, members }, ctx),
+                            Ok(Err(message)) => act.send_event(Event::Error { message }, ctx),
+                            _ => println!("Something is wrong"),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+            }
+            Command::Join { room, password } => {
+                self.session()
+                    .addr
+                    .send(server::Join {
+                        id,
+                        room: room.clone(),
+                        password,
+                    })
+                    .into_actor(self)
+                    .then(|res, act, ctx| {
+                        match res {
+                            Ok(Ok(())) => {
+                                let session = act.session();
+                                if !session.rooms.contains(&room) {
+                                    session.rooms.push(room.clone());
+                                }
+                                session.room = room.clone();
+                                act.send_event(Event::Joined { room }, ctx);
+                            }
+                            Ok(Err(message)) => act.send_event(Event::Error { message }, ctx),
+                            _ => println!("Something is wrong"),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+            }
+            Command::Invite { name, room } => {
+                let room = room.unwrap_or(current);
+                self.request(server::Invite { id, name, room }, ctx);
+            }
+            Command::Kick { name, room } => self.moderate(name, room, server::Action::Kick, ctx),
+            Command::Ban { name, room } => self.moderate(name, room, server::Action::Ban, ctx),
+            Command::Unban { name, room } => self.moderate(name, room, server::Action::Unban, ctx),
+            Command::Op { name, room } => self.moderate(name, room, server::Action::Op, ctx),
+            Command::Mute { name, seconds, room } => {
+                let action = server::Action::Mute(Duration::from_secs(seconds));
+                self.moderate(name, room, action, ctx)
+            }
+            Command::Upload { room } => {
+                let room = room.unwrap_or(current);
+                self.session()
+                    .addr
+                    .send(server::UploadTicket {
+                        id,
+                        room: room.clone(),
+                    })
+                    .into_actor(self)
+                    .then(|res, act, ctx| {
+                        match res {
+                            Ok(Ok(ticket)) => {
+                                let url = format!("/upload/{}", ticket);
+                                act.send_event(Event::Upload { room, url }, ctx);
+                            }
+                            Ok(Err(message)) => act.send_event(Event::Error { message }, ctx),
+                            _ => println!("Something is wrong"),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+            }
+            Command::Typing { room, typing } => {
+                let room = room.unwrap_or(current);
+                self.request(server::Typing { id, room, typing }, ctx);
+            }
+            Command::Away { message } => {
+                let presence = protocol::Presence::Away;
+                self.request(server::SetPresence { id, presence, message }, ctx);
+            }
+            Command::Back => {
+                let presence = protocol::Presence::Online;
+                self.request(server::SetPresence { id, presence, message: None }, ctx);
+            }
+            Command::Ack { room, seq, read } => {
+                let room = room.unwrap_or(current);
+                self.request(server::Ack { id, room, seq, read }, ctx);
+            }
+            Command::History { room, since } => {
+                let room = room.unwrap_or(current);
+                self.request(server::Replay { id, room, since }, ctx);
+            }
+            Command::Configure { room, private, password } => {
+                let room = room.unwrap_or(current);
+                self.request(
+                    server::Configure {
+                        id,
+                        room,
+                        private,
+                        password,
+                    },
+                    ctx,
+                );
+            }
+            Command::Leave { room } => {
+                let room = room.unwrap_or(current);
+                self.session()
+                    .addr
+                    .send(server::Leave {
+                        id,
+                        room: room.clone(),
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(Ok(())) => {
+                                act.left(&room, ctx);
+                                act.send_event(Event::Left { room }, ctx);
+                            }
+                            Ok(Err(message)) => act.send_event(Event::Error { message }, ctx),
+                            _ => println!("Something is wrong"),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+            }
+            Command::Switch { room } => {
+                if self.session().rooms.contains(&room) {
+                    self.session().room = room.clone();
+                    self.send_event(Event::Active { room }, ctx);
+                } else {
+                    let message = format!("you are not in {}", room);
+                    self.send_event(Event::Error { message }, ctx);
+                }
+            }
+            Command::Name { name } => {
+                self.session()
+                    .addr
+                    .send(server::SetName {
+                        id,
+                        name: name.clone(),
+                    })
+                    .into_actor(self)
+                    .then(|res, act, ctx| {
+                        match res {
+                            Ok(Ok(())) => act.session().name = Some(name),
+                            Ok(Err(message)) => act.send_event(Event::Error { message }, ctx),
+                            _ => println!("Something is wrong"),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+            }
+            Command::Msg { to, text } => {
+                let msg = server::PrivateMessage { id, to, msg: text };
+                self.request(msg, ctx);
+            }
+            Command::Message { text, room } => {
+                // send message to chat server, it prefixes our name
+                self.session().addr.do_send(server::ClientMessage {
+                    id,
+                    msg: text,
+                    room: room.unwrap_or(current),
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use actix::prelude::*;
+use actix_web::{error, http::header, web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws::CloseCode;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use std::time::Duration;
+
+use crate::auth::Users;
+use crate::protocol::{Event, Format};
+use crate::server;
+use crate::shutdown::Draining;
+use crate::session::{ChatSession, Input, Inputs, Transport};
+
+/// Events waiting to be written out before the stream counts as stuck
+const STREAM_BUFFER: usize = 64;
+/// A comment goes out this often, so proxies keep a quiet stream open and
+/// we learn when the client is gone
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A chat session streaming its events as Server-Sent Events, for clients
+/// behind proxies that break WebSockets. Input comes in by POST.
+struct SseSession {
+    session: ChatSession,
+    tx: mpsc::Sender<Bytes>,
+    inputs: web::Data<Inputs>,
+    token: Option<String>,
+}
+
+impl Actor for SseSession {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.connect(ctx);
+        ctx.run_interval(KEEPALIVE_INTERVAL, |act, ctx| {
+            act.write(Bytes::from_static(b": keepalive\n\n"), ctx);
+            act.session.check_idle();
+        });
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        println!("SSE chat session [{}] is stopping", self.session.id);
+        if let Some(ref token) = self.token {
+            self.inputs.remove(token, self.session.conn);
+        }
+        self.disconnect();
+        Running::Stop
+    }
+}
+
+impl Handler<server::Message> for SseSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: server::Message, ctx: &mut Self::Context) -> Self::Result {
+        self.handle_event(msg.0, ctx);
+    }
+}
+
+impl Handler<Input> for SseSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Input, ctx: &mut Self::Context) -> Self::Result {
+        self.handle_input(&msg.0, ctx);
+    }
+}
+
+impl Transport for SseSession {
+    fn session(&mut self) -> &mut ChatSession {
+        &mut self.session
+    }
+
+    fn send_event(&mut self, event: Event, ctx: &mut Context<Self>) {
+        match self.session.format {
+            Format::Text => {
+                for line in event.lines() {
+                    self.write(frame(&line), ctx);
+                }
+            }
+            Format::Json => self.write(frame(&event.to_json()), ctx),
+        }
+    }
+
+    fn close(&mut self, _: CloseCode, _: String, ctx: &mut Context<Self>) {
+        // Dropping the sender ends the stream
+        ctx.stop();
+    }
+
+    fn attached(&mut self, token: &str, ctx: &mut Context<Self>) {
+        let input = ctx.address().recipient();
+        self.inputs.insert(token.to_owned(), self.session.conn, input);
+        self.token = Some(token.to_owned());
+    }
+}
+
+impl SseSession {
+    fn write(&mut self, data: Bytes, ctx: &mut Context<Self>) {
+        if let Err(e) = self.tx.try_send(data) {
+            if e.is_full() {
+                println!("SSE chat session [{}] is not keeping up", self.session.id);
+            }
+            ctx.stop();
+        }
+    }
+}
+
+/// An SSE message, every line of the data on a `data:` line of its own
+fn frame(data: &str) -> Bytes {
+    let mut frame = String::with_capacity(data.len() + 8);
+    for line in data.split('\n') {
+        frame.push_str("data: ");
+        frame.push_str(line);
+        frame.push('\n');
+    }
+    frame.push('\n');
+    Bytes::from(frame)
+}
+
+/// Stream the events of a new or resumed session
+pub async fn events(
+    req: HttpRequest,
+    users: web::Data<Users>,
+    draining: web::Data<Draining>,
+    inputs: web::Data<Inputs>,
+    srv: web::Data<Addr<server::ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    draining.check()?;
+    let session = ChatSession::from_request(&req, &users, srv.get_ref().clone())?;
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+    SseSession {
+        session,
+        tx,
+        inputs: inputs.clone(),
+        token: None,
+    }
+    .start();
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .streaming(rx.map(Ok::<_, Error>)))
+}
+
+/// A line of input for the session streaming with the given token, what a
+/// WebSocket client would send as a text message
+pub async fn input(
+    token: web::Path<String>,
+    body: String,
+    inputs: web::Data<Inputs>,
+) -> Result<HttpResponse, Error> {
+    let input = inputs
+        .get(&token)
+        .ok_or_else(|| error::ErrorNotFound("no such session"))?;
+    input
+        .do_send(Input(body))
+        .map_err(|_| error::ErrorNotFound("no such session"))?;
+    Ok(HttpResponse::Accepted().finish())
+}
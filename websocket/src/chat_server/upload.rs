@@ -0,0 +1,185 @@
+use actix::Addr;
+use actix_files::NamedFile;
+use actix_multipart::Multipart;
+use actix_web::http::header::{
+    self, ContentDisposition, ContentType, DispositionParam, DispositionType, HeaderValue,
+};
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use futures::{StreamExt, TryStreamExt};
+use rand::Rng;
+use std::io::Write;
+use std::path::PathBuf;
+
+use config::{Config, Size};
+use crate::protocol::query_param;
+use crate::server;
+
+pub const UPLOAD_DIR: &str = "./uploads";
+pub const MAX_UPLOAD_SIZE: usize = 10 * 1024 * 1024;
+/// Header a download names its session in. The `session` query parameter
+/// works too so a plain link can be followed, but ends up in access logs.
+pub const SESSION_HEADER: &str = "x-chat-session";
+
+/// Where uploads are stored and how big one can get
+#[derive(Clone, Debug)]
+pub struct Uploads {
+    pub dir: PathBuf,
+    /// Bytes an upload may carry, over all of its files
+    pub max_size: usize,
+}
+
+impl Uploads {
+    /// The `[upload]` settings
+    pub fn from_config(config: &mut Config) -> Uploads {
+        let uploads = Uploads {
+            dir: PathBuf::from(config.get("upload.dir", String::from(UPLOAD_DIR))),
+            max_size: config.get("upload.max_size", Size(MAX_UPLOAD_SIZE)).0,
+        };
+        config.check("upload.dir", !uploads.dir.as_os_str().is_empty(), "must not be empty");
+        config.check("upload.max_size", uploads.max_size > 0, "must be above zero");
+        uploads
+    }
+}
+
+/// Store the files of a multipart upload and share them in the room the
+/// ticket was issued for
+pub async fn upload(
+    ticket: web::Path<String>,
+    mut payload: Multipart,
+    uploads: web::Data<Uploads>,
+    srv: web::Data<Addr<server::ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    let (id, room) = srv
+        .send(server::RedeemUpload {
+            ticket: ticket.into_inner(),
+        })
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .map_err(error::ErrorForbidden)?;
+
+    let mut total = 0;
+    while let Ok(Some(mut field)) = payload.try_next().await {
+        let name = field
+            .content_disposition()
+            .and_then(|cd| cd.get_filename().map(sanitize))
+            .ok_or(error::ParseError::Incomplete)?;
+        // Prefix a random tag so uploads never overwrite each other
+        let stored = format!("{:016x}-{}", rand::thread_rng().gen::<u64>(), name);
+        // Every room gets a directory so a download knows whose members may have it
+        let dir = uploads.dir.join(room_dir(&room));
+        let filepath = dir.join(&stored);
+
+        // Creating file using std::fs::File::create is blocking,
+        // so we hand this work to thread pool
+        let path = filepath.clone();
+        let mut f = web::block(move || {
+            std::fs::create_dir_all(dir)?;
+            std::fs::File::create(path)
+        })
+        .await?;
+
+        let mut size = 0;
+        while let Some(chunk) = field.next().await {
+            let data = chunk?;
+            size += data.len();
+            total += data.len();
+            if total > uploads.max_size {
+                drop(f);
+                let _ = web::block(move || std::fs::remove_file(filepath)).await;
+                return Err(error::ErrorPayloadTooLarge("upload too big"));
+            }
+            f = web::block(move || f.write_all(&data).map(|_| f)).await?;
+        }
+
+        srv.do_send(server::ShareFile {
+            id,
+            room: room.clone(),
+            name,
+            size,
+            url: format!("/files/{}/{}", room_dir(&room), stored),
+        });
+    }
+    Ok(HttpResponse::Ok().into())
+}
+
+/// Hand a shared file to a member of its room. It always comes as an
+/// attachment of no particular type, so a browser never renders it on our
+/// origin.
+pub async fn download(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    uploads: web::Data<Uploads>,
+    srv: web::Data<Addr<server::ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    let (dir, stored) = path.into_inner();
+    let room = room_name(&dir).ok_or_else(|| error::ErrorNotFound("no such file"))?;
+    if stored != sanitize(&stored) {
+        return Err(error::ErrorNotFound("no such file"));
+    }
+    let token = session_token(&req).ok_or_else(|| error::ErrorUnauthorized("session required"))?;
+    srv.send(server::FileAccess { token, room })
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .map_err(error::ErrorForbidden)?;
+
+    // Drop the random tag again for the name the file is saved under
+    let name = stored.split_once('-').map_or(&*stored, |(_, name)| name).to_owned();
+    let file = NamedFile::open(uploads.dir.join(&dir).join(&stored))
+        .map_err(|_| error::ErrorNotFound("no such file"))?
+        .set_content_type(ContentType::octet_stream().0)
+        .set_content_disposition(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(name)],
+        });
+    let mut res = file.into_response(&req)?;
+    res.headers_mut().insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    Ok(res)
+}
+
+/// The session token of a download, see `SESSION_HEADER`
+fn session_token(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(SESSION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| query_param(req.query_string(), "session"))
+        .map(str::to_owned)
+}
+
+/// The directory a room's uploads go in, hex so any room name is a safe
+/// path and URL segment
+fn room_dir(room: &str) -> String {
+    room.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The room an upload directory belongs to
+fn room_name(dir: &str) -> Option<String> {
+    if dir.is_empty() || !dir.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes = (0..dir.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(dir.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Keep just the base name and a safe set of characters
+fn sanitize(filename: &str) -> String {
+    let base = filename.rsplit(['/', '\\']).next().unwrap_or("");
+    let name: String = base
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    let name = name.trim_start_matches('.');
+    if name.is_empty() {
+        String::from("file")
+    } else {
+        name.to_owned()
+    }
+}
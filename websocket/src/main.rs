@@ -1,16 +1,44 @@
-use actix::prelude::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix::prelude::{Actor, ActorContext, Addr, AsyncContext, Handler, Message, Running, StreamHandler};
 use actix_files as fs;
 use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer, middleware};
 use actix_web_actors::ws;
 
-use std::time::{Duration, Instant};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Instant;
 
-const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+mod reassembly;
+mod shutdown;
+use config::{default_workers, Config, Heartbeat, Size};
+use reassembly::{too_big, Reassembler, MAX_MESSAGE_SIZE};
 
-async fn ws_index(r: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
+/// What every connection starts with
+#[derive(Clone, Copy)]
+struct Settings {
+    heartbeat: Heartbeat,
+    max_message_size: usize,
+}
+
+/// Every open connection, so they can be closed on shutdown
+#[derive(Default)]
+struct Connections(Mutex<Vec<Addr<MyWebsocket>>>);
+
+/// Close the connection, the server is going down
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Shutdown;
+
+async fn ws_index(
+    r: HttpRequest,
+    stream: web::Payload,
+    connections: web::Data<Connections>,
+    draining: web::Data<shutdown::Draining>,
+    settings: web::Data<Settings>,
+) -> Result<HttpResponse, Error> {
+    draining.check()?;
     println!("{:?}", r);
-    let res = ws::start(MyWebsocket::new(), &r, stream);
+    let max_size = settings.max_message_size;
+    let res = reassembly::start(MyWebsocket::new(connections, **settings), max_size, &r, stream);
     println!("{:?}", res);
     res
 }
@@ -18,16 +46,24 @@ async fn ws_index(r: HttpRequest, stream: web::Payload) -> Result<HttpResponse,
 // MyWebsocket: Actor<Context = WebsocketContext<MyWebsocket>> + StreamHandler<Result<ws::Message, ws::ProtocolError>>
 struct MyWebsocket {
     hb: Instant,
+    heartbeat: Heartbeat,
+    fragments: Reassembler,
+    connections: web::Data<Connections>,
 }
 
 impl MyWebsocket {
-    fn new() -> Self {
-        Self { hb: Instant::now() }
+    fn new(connections: web::Data<Connections>, settings: Settings) -> Self {
+        Self {
+            hb: Instant::now(),
+            heartbeat: settings.heartbeat,
+            fragments: Reassembler::new(settings.max_message_size),
+            connections,
+        }
     }
 
     fn hb(&mut self, ctx: &mut <Self as Actor>::Context) {
-        ctx.run_interval(HEARTBEAT_TIMEOUT, |act, ctx| {
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+        ctx.run_interval(self.heartbeat.interval, |act, ctx| {
+            if Instant::now().duration_since(act.hb) > act.heartbeat.timeout {
                 println!("Websocket client ping timeout, closing connection");
                 ctx.stop();
                 return;
@@ -42,6 +78,25 @@ impl Actor for MyWebsocket {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         self.hb(ctx);
+        self.connections.0.lock().unwrap().push(ctx.address());
+    }
+
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
+        let addr = ctx.address();
+        self.connections.0.lock().unwrap().retain(|other| *other != addr);
+        Running::Stop
+    }
+}
+
+impl Handler<Shutdown> for MyWebsocket {
+    type Result = ();
+
+    fn handle(&mut self, _: Shutdown, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some(String::from("server is shutting down")),
+        }));
+        ctx.stop();
     }
 }
 
@@ -62,6 +117,21 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWebsocket {
                 println!("Close message received, closing connection");
                 ctx.stop();
             }
+            Ok(ws::Message::Continuation(item)) => match self.fragments.push(item) {
+                Ok(Some(msg)) => StreamHandler::handle(self, Ok(msg), ctx),
+                Ok(None) => (),
+                Err(reason) => {
+                    println!("Bad fragmented message, closing connection: {:?}", reason);
+                    ctx.close(Some(reason));
+                    ctx.stop();
+                }
+            },
+            Ok(ws::Message::Nop) => (),
+            Err(ws::ProtocolError::Overflow) => {
+                println!("Message too big, closing connection");
+                ctx.close(Some(too_big()));
+                ctx.stop();
+            }
             _ => {
                 println!("Unknown message received, closing connection");
                 ctx.stop();
@@ -72,16 +142,64 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWebsocket {
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
+    let mut config = Config::load("ws-server", "WS");
+    let bind = config.get("server.bind", SocketAddr::from(([0, 0, 0, 0], 9999)));
+    let workers = config.get("server.workers", default_workers());
+    config.check("server.workers", workers > 0, "must be at least 1");
+    let drain_timeout = config.get("server.drain_timeout", shutdown::DRAIN_TIMEOUT);
+    let static_dir = config.get("server.static_dir", String::from("static/"));
+    let settings = Settings {
+        heartbeat: Heartbeat::from_config(&mut config),
+        max_message_size: config.get("server.max_message_size", Size(MAX_MESSAGE_SIZE)).0,
+    };
+    config.check("server.max_message_size", settings.max_message_size > 0, "must be above zero");
+    config.finish()?;
+    if config.print {
+        print!("{}", config);
+        return Ok(());
+    }
+
     std::env::set_var("RUST_LOG", "actix_server=info,actix_web=info");
     env_logger::init();
 
-    HttpServer::new(|| {
+    let connections = web::Data::new(Connections::default());
+    let draining = web::Data::new(shutdown::Draining::default());
+    let open = connections.clone();
+    let closing = draining.clone();
+    let http = HttpServer::new(move || {
         App::new()
+            .app_data(open.clone())
+            .app_data(closing.clone())
+            .data(settings)
             .wrap(middleware::Logger::default())
             .service(web::resource("/ws/").route(web::get().to(ws_index)))
-            .service(fs::Files::new("/", "static/").index_file("index.html"))
+            .service(fs::Files::new("/", &static_dir).index_file("index.html"))
     })
-    .bind("0.0.0.0:9999")?
-    .run()
-    .await
+    .workers(workers)
+    .bind(bind)?
+    .disable_signals()
+    .run();
+
+    let graceful = http.clone();
+    actix_rt::spawn(async move {
+        match shutdown::signalled().await {
+            Ok(signal) => println!("{} received, shutting down", signal),
+            Err(e) => return println!("Can not listen for signals: {}", e),
+        }
+        // No new connections, then close the ones there are
+        draining.start();
+        for addr in connections.0.lock().unwrap().iter() {
+            addr.do_send(Shutdown);
+        }
+        let drained = shutdown::drain(drain_timeout, || {
+            let open = connections.0.lock().unwrap().len();
+            async move { open == 0 }
+        })
+        .await;
+        if !drained {
+            println!("Connections still open after {:?}, stopping anyway", drain_timeout);
+        }
+        graceful.stop(true).await;
+    });
+    http.await
 }
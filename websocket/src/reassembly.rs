@@ -0,0 +1,152 @@
+use actix::{Actor, StreamHandler};
+use actix_http::ws::{Codec, Item};
+use actix_web::error::PayloadError;
+use actix_web::{Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use bytes::{Bytes, BytesMut};
+use futures::Stream;
+
+/// Largest message we are willing to assemble from fragments
+pub const MAX_MESSAGE_SIZE: usize = 256 * 1024;
+
+enum Kind {
+    Text,
+    Binary,
+}
+
+/// Collects continuation frames until a fragmented message is complete
+pub struct Reassembler {
+    max_size: usize,
+    kind: Option<Kind>,
+    buf: BytesMut,
+}
+
+impl Reassembler {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            kind: None,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// Feed the next fragment, returns the whole message once the last one
+    /// is in. An error carries the reason to close the connection with.
+    pub fn push(&mut self, item: Item) -> Result<Option<ws::Message>, ws::CloseReason> {
+        let (data, last) = match item {
+            Item::FirstText(data) => (self.start(Kind::Text, data)?, false),
+            Item::FirstBinary(data) => (self.start(Kind::Binary, data)?, false),
+            Item::Continue(data) => (data, false),
+            Item::Last(data) => (data, true),
+        };
+        if self.kind.is_none() {
+            return Err(close(ws::CloseCode::Protocol, "continuation without a first frame"));
+        }
+        if self.buf.len() + data.len() > self.max_size {
+            self.reset();
+            return Err(too_big());
+        }
+        self.buf.extend_from_slice(&data);
+        if !last {
+            return Ok(None);
+        }
+
+        let data = self.buf.split().freeze();
+        match self.kind.take() {
+            Some(Kind::Text) => match String::from_utf8(data.to_vec()) {
+                Ok(text) => Ok(Some(ws::Message::Text(text))),
+                Err(_) => Err(close(ws::CloseCode::Invalid, "text is not valid utf-8")),
+            },
+            _ => Ok(Some(ws::Message::Binary(data))),
+        }
+    }
+
+    fn start(&mut self, kind: Kind, data: Bytes) -> Result<Bytes, ws::CloseReason> {
+        if self.kind.is_some() {
+            self.reset();
+            return Err(close(ws::CloseCode::Protocol, "new message before the last one ended"));
+        }
+        self.kind = Some(kind);
+        Ok(data)
+    }
+
+    fn reset(&mut self) {
+        self.kind = None;
+        self.buf.clear();
+    }
+}
+
+/// Start a WebSocket actor taking single frames of up to `max_size` bytes,
+/// the limit the reassembler puts on fragmented messages. A bigger frame
+/// reaches the actor as `ProtocolError::Overflow`.
+pub fn start<A, S>(actor: A, max_size: usize, req: &HttpRequest, stream: S) -> Result<HttpResponse, Error>
+where
+    A: Actor<Context = ws::WebsocketContext<A>> + StreamHandler<Result<ws::Message, ws::ProtocolError>>,
+    S: Stream<Item = Result<Bytes, PayloadError>> + 'static,
+{
+    let codec = Codec::new().max_size(max_size);
+    Ok(ws::handshake(req)?.streaming(ws::WebsocketContext::with_codec(actor, stream, codec)))
+}
+
+/// What to close a connection with when a message is over the limit
+pub fn too_big() -> ws::CloseReason {
+    close(ws::CloseCode::Size, "message too big")
+}
+
+fn close(code: ws::CloseCode, description: &str) -> ws::CloseReason {
+    ws::CloseReason {
+        code,
+        description: Some(description.to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(res: Result<Option<ws::Message>, ws::CloseReason>) -> ws::CloseCode {
+        res.expect_err("the message should be refused").code
+    }
+
+    #[test]
+    fn joins_the_fragments_of_a_message() {
+        let mut fragments = Reassembler::new(16);
+        assert_eq!(fragments.push(Item::FirstText(Bytes::from_static(b"hel"))), Ok(None));
+        assert_eq!(fragments.push(Item::Continue(Bytes::from_static(b"lo "))), Ok(None));
+        let msg = fragments.push(Item::Last(Bytes::from_static(b"you")));
+        assert_eq!(msg, Ok(Some(ws::Message::Text(String::from("hello you")))));
+
+        // And is ready for the next one
+        assert_eq!(fragments.push(Item::FirstBinary(Bytes::from_static(b"\xff"))), Ok(None));
+        let msg = fragments.push(Item::Last(Bytes::from_static(b"\x00")));
+        assert_eq!(msg, Ok(Some(ws::Message::Binary(Bytes::from_static(b"\xff\x00")))));
+    }
+
+    #[test]
+    fn refuses_a_message_over_the_limit() {
+        let mut fragments = Reassembler::new(4);
+        assert_eq!(fragments.push(Item::FirstText(Bytes::from_static(b"abc"))), Ok(None));
+        assert_eq!(code(fragments.push(Item::Continue(Bytes::from_static(b"de")))), ws::CloseCode::Size);
+    }
+
+    #[test]
+    fn refuses_a_continuation_without_a_first_frame() {
+        let mut fragments = Reassembler::new(16);
+        assert_eq!(code(fragments.push(Item::Continue(Bytes::from_static(b"a")))), ws::CloseCode::Protocol);
+        assert_eq!(code(fragments.push(Item::Last(Bytes::from_static(b"a")))), ws::CloseCode::Protocol);
+    }
+
+    #[test]
+    fn refuses_a_new_message_before_the_last_one_ended() {
+        let mut fragments = Reassembler::new(16);
+        assert_eq!(fragments.push(Item::FirstText(Bytes::from_static(b"a"))), Ok(None));
+        assert_eq!(code(fragments.push(Item::FirstBinary(Bytes::from_static(b"b")))), ws::CloseCode::Protocol);
+    }
+
+    #[test]
+    fn refuses_text_that_is_not_utf8() {
+        let mut fragments = Reassembler::new(16);
+        assert_eq!(fragments.push(Item::FirstText(Bytes::from_static(b"\xc3"))), Ok(None));
+        assert_eq!(code(fragments.push(Item::Last(Bytes::from_static(b"\x28")))), ws::CloseCode::Invalid);
+    }
+}
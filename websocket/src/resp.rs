@@ -0,0 +1,207 @@
+use actix_codec::{Decoder, Encoder};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io;
+
+/// Largest bulk string we accept, anything bigger is a broken peer
+pub const MAX_BULK_SIZE: usize = 16 * 1024 * 1024;
+/// Longest line of a value header
+const MAX_LINE: usize = 64 * 1024;
+/// Room for writes waiting on the socket
+pub const WRITE_QUEUE: usize = 64;
+/// How deep arrays may nest, pushes and commands are flat so this is plenty
+const MAX_DEPTH: usize = 8;
+
+/// A value of the Redis serialization protocol
+#[derive(Clone, Debug, PartialEq)]
+pub enum Resp {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Bytes>),
+    Array(Option<Vec<Resp>>),
+}
+
+impl Resp {
+    /// A command or a push, as an array of bulk strings
+    pub fn array<I, B>(parts: I) -> Resp
+    where
+        I: IntoIterator<Item = B>,
+        B: Into<Bytes>,
+    {
+        let parts = parts.into_iter().map(|part| Resp::Bulk(Some(part.into())));
+        Resp::Array(Some(parts.collect()))
+    }
+
+    /// The parts of an array of strings, which is what commands and pushes
+    /// are made of
+    pub fn into_parts(self) -> Option<Vec<Bytes>> {
+        match self {
+            Resp::Array(Some(items)) => items
+                .into_iter()
+                .map(|item| match item {
+                    Resp::Bulk(Some(data)) => Some(data),
+                    Resp::Simple(text) => Some(Bytes::from(text)),
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RespCodec;
+
+impl Decoder for RespCodec {
+    type Item = Resp;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Resp>, io::Error> {
+        match parse(src, 0)? {
+            Some((value, len)) => {
+                src.advance(len);
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder for RespCodec {
+    type Item = Resp;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Resp, dst: &mut BytesMut) -> Result<(), io::Error> {
+        match item {
+            Resp::Simple(text) => line(dst, b'+', text.as_bytes()),
+            Resp::Error(text) => line(dst, b'-', text.as_bytes()),
+            Resp::Integer(n) => line(dst, b':', n.to_string().as_bytes()),
+            Resp::Bulk(None) => dst.extend_from_slice(b"$-1\r\n"),
+            Resp::Array(None) => dst.extend_from_slice(b"*-1\r\n"),
+            Resp::Bulk(Some(data)) => {
+                line(dst, b'$', data.len().to_string().as_bytes());
+                dst.extend_from_slice(&data);
+                dst.extend_from_slice(b"\r\n");
+            }
+            Resp::Array(Some(items)) => {
+                line(dst, b'*', items.len().to_string().as_bytes());
+                for item in items {
+                    self.encode(item, dst)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn line(dst: &mut BytesMut, kind: u8, text: &[u8]) {
+    dst.reserve(text.len() + 3);
+    dst.put_u8(kind);
+    dst.extend_from_slice(text);
+    dst.extend_from_slice(b"\r\n");
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+/// Parse one value off the front of `src`, with the number of bytes it
+/// took, `depth` being how many arrays it is inside of. `None` means more
+/// data is needed.
+fn parse(src: &[u8], depth: usize) -> io::Result<Option<(Resp, usize)>> {
+    let end = match src.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if src.len() > MAX_LINE => return Err(invalid("line too long")),
+        None => return Ok(None),
+    };
+    if end == 0 {
+        return Err(invalid("empty line"));
+    }
+    let text = std::str::from_utf8(&src[1..end]).map_err(|_| invalid("line is not utf-8"))?;
+    let rest = end + 2;
+    let number = || text.parse::<i64>().map_err(|_| invalid("bad number"));
+
+    let (value, len) = match src[0] {
+        b'+' => (Resp::Simple(text.to_owned()), rest),
+        b'-' => (Resp::Error(text.to_owned()), rest),
+        b':' => (Resp::Integer(number()?), rest),
+        b'$' => {
+            let size = number()?;
+            if size < 0 {
+                return Ok(Some((Resp::Bulk(None), rest)));
+            }
+            let size = size as usize;
+            if size > MAX_BULK_SIZE {
+                return Err(invalid("bulk string too big"));
+            }
+            if src.len() < rest + size + 2 {
+                return Ok(None);
+            }
+            if &src[rest + size..rest + size + 2] != b"\r\n" {
+                return Err(invalid("bulk string not terminated"));
+            }
+            let data = Bytes::copy_from_slice(&src[rest..rest + size]);
+            (Resp::Bulk(Some(data)), rest + size + 2)
+        }
+        b'*' => {
+            let count = number()?;
+            if count < 0 {
+                return Ok(Some((Resp::Array(None), rest)));
+            }
+            if depth >= MAX_DEPTH {
+                return Err(invalid("arrays nested too deep"));
+            }
+            let mut items = Vec::new();
+            let mut len = rest;
+            for _ in 0..count {
+                match parse(&src[len..], depth + 1)? {
+                    Some((item, used)) => {
+                        items.push(item);
+                        len += used;
+                    }
+                    None => return Ok(None),
+                }
+            }
+            (Resp::Array(Some(items)), len)
+        }
+        _ => return Err(invalid("unknown value type")),
+    };
+    Ok(Some((value, len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_survive_a_round_trip_in_pieces() {
+        let value = Resp::Array(Some(vec![
+            Resp::Bulk(Some(Bytes::from_static(b"message"))),
+            Resp::Bulk(Some(Bytes::from_static(b"with\r\ninside"))),
+            Resp::Integer(-3),
+            Resp::Bulk(None),
+        ]));
+        let mut buf = BytesMut::new();
+        RespCodec.encode(value.clone(), &mut buf).unwrap();
+
+        // Nothing comes out until the last byte is in
+        let mut partial = BytesMut::new();
+        for byte in &buf[..buf.len() - 1] {
+            partial.put_u8(*byte);
+            assert_eq!(RespCodec.decode(&mut partial).unwrap(), None);
+        }
+        partial.put_u8(buf[buf.len() - 1]);
+        assert_eq!(RespCodec.decode(&mut partial).unwrap(), Some(value));
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn deeply_nested_arrays_are_refused() {
+        let mut nested = BytesMut::from(&b"*1\r\n".repeat(100_000)[..]);
+        assert!(RespCodec.decode(&mut nested).is_err());
+
+        let mut shallow = BytesMut::from(&b"*1\r\n".repeat(MAX_DEPTH)[..]);
+        shallow.extend_from_slice(b":1\r\n");
+        assert!(RespCodec.decode(&mut shallow).unwrap().is_some());
+    }
+}
@@ -0,0 +1,61 @@
+use actix_rt::signal::unix::{signal, SignalKind};
+use actix_rt::time::delay_for;
+use actix_web::{error, Error};
+use futures::future::{self, Either};
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// How long connections get to close before the server stops anyway
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Set once the server starts shutting down. Requests already in flight
+/// carry on, new connections are turned away.
+#[derive(Default)]
+pub struct Draining(AtomicBool);
+
+impl Draining {
+    pub fn start(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Refuse with a 503 while draining
+    pub fn check(&self) -> Result<(), Error> {
+        if self.0.load(Ordering::SeqCst) {
+            return Err(error::ErrorServiceUnavailable("server is shutting down"));
+        }
+        Ok(())
+    }
+}
+
+/// Wait for SIGINT or SIGTERM, returning the one that came
+pub async fn signalled() -> io::Result<&'static str> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let signal = match future::select(Box::pin(interrupt.recv()), Box::pin(terminate.recv())).await {
+        Either::Left(_) => "SIGINT",
+        Either::Right(_) => "SIGTERM",
+    };
+    Ok(signal)
+}
+
+/// Wait until `drained` says everything is closed, or `timeout` is up.
+/// Returns whether it drained in time.
+pub async fn drain<F, Fut>(timeout: Duration, mut drained: F) -> bool
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        if drained().await {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        delay_for(DRAIN_CHECK_INTERVAL).await;
+    }
+}